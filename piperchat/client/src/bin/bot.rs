@@ -0,0 +1,29 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+
+use piperchat::bot::{self, BotConfig};
+
+#[derive(Debug, clap::Parser)]
+struct Args {
+    /// Path to the bot's TOML configuration file
+    config: PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    pretty_env_logger::init();
+
+    let config = BotConfig::load(&args.config)?;
+
+    async_std::task::block_on(async move {
+        let (client, events) = bot::connect(&config).await?;
+
+        let ctrlc_client = client.clone();
+        ctrlc::set_handler(move || ctrlc_client.disconnect())
+            .context("Error setting Ctrl-C handler")?;
+
+        bot::run(&config, client, events).await
+    })
+}
@@ -0,0 +1,152 @@
+use anyhow::Context;
+use clap::Parser;
+use gtk::glib::{self, clone};
+use gtk::prelude::ApplicationExtManual;
+use gtk::{gio, prelude::ApplicationExt};
+use log::debug;
+use rand::Rng;
+use std::path::PathBuf;
+
+use piperchat as pc;
+use piperchat::client::Client;
+use piperchat::headless::HeadlessOptions;
+use piperchat::settings::Settings;
+use piperchat::APP_ID;
+
+/// Options given here override the ones from the config file for this run only
+#[derive(Debug, clap::Parser)]
+struct Args {
+    /// Config file to use instead of piperchat/config.toml in the user's config directory
+    #[arg(long)]
+    config: Option<PathBuf>,
+    #[arg(short, long)]
+    server: Option<String>,
+    /// Run without the GUI, controlled by commands from stdin
+    #[arg(long)]
+    headless: bool,
+    /// Name to register with, required in headless mode unless set in the config file
+    #[arg(short, long)]
+    name: Option<String>,
+    /// Headless: call this user (name or id) once they're online
+    #[arg(long, requires = "headless")]
+    call: Option<String>,
+    /// Headless: accept all incoming calls
+    #[arg(long, requires = "headless")]
+    auto_accept: bool,
+    /// Send a summary of the call quality to the server at hangup
+    #[arg(long)]
+    report_stats: bool,
+    /// Lowest video bitrate to adapt down to, in bits per second
+    #[arg(long)]
+    min_bitrate: Option<u32>,
+    /// Highest video bitrate to adapt up to, in bits per second
+    #[arg(long)]
+    max_bitrate: Option<u32>,
+    /// Don't cancel the echo of the peer's audio from the microphone
+    #[arg(long)]
+    no_echo_cancel: bool,
+    /// Don't suppress background noise picked up by the microphone
+    #[arg(long)]
+    no_noise_suppression: bool,
+    /// Don't adjust the microphone volume automatically
+    #[arg(long)]
+    no_gain_control: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    pretty_env_logger::init();
+    let settings_path = args.config.clone().unwrap_or_else(Settings::default_path);
+    let mut settings = Settings::load(&settings_path)?;
+    apply_overrides(&mut settings, &args);
+
+    if args.headless {
+        let name = settings.name.clone().context(
+            "a name is required in headless mode, pass --name or set it in the config file",
+        )?;
+        let options = HeadlessOptions {
+            call: args.call,
+            auto_accept: args.auto_accept,
+        };
+
+        return async_std::task::block_on(async move {
+            let (client, events) = Client::connect(&settings.server, &name, settings.call).await?;
+
+            let ctrlc_client = client.clone();
+            ctrlc::set_handler(move || ctrlc_client.disconnect())
+                .context("Error setting Ctrl-C handler")?;
+
+            pc::headless::run(client, events, options).await
+        });
+    }
+
+    gst::init()?;
+
+    // So apparently GTK, when executing multiple instances of an application with the same APP_ID, will take the window
+    // from the newly spawned instance and give it to the previously spawned instance, and then exit the new instance,
+    // so there's only one process controlling all the windows. This might be helpful with saving system resources for
+    // some applications, if they are written in a sane way, but as this project is very cursed and I have no idea how
+    // to write GTK applications, I'm going to have none of that and pretend to GTK that these are separate applications
+    // so that it doesn't hijack the window and prematurely kill the process.
+    let rand_proc_id: String = rand::thread_rng()
+        .sample_iter(rand::distributions::Uniform::new(
+            char::from(97),
+            char::from(122),
+        ))
+        .take(8)
+        .map(char::from)
+        .collect();
+    let app_id = format!("{APP_ID}.{rand_proc_id}");
+    debug!("appid = {app_id}");
+
+    // GTK
+    // Register and include resources
+    gio::resources_register_include!("piperchat.gresource").expect("Failed to register resources.");
+
+    // Create a new application
+    let app = adw::Application::builder().application_id(&app_id).build();
+
+    // Connect signals
+    app.connect_activate(move |app| pc::build_ui(app, settings.clone(), settings_path.clone()));
+
+    // the signal handler runs on its own thread, quit from the main loop instead
+    let (exit_tx, exit_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    ctrlc::set_handler(move || {
+        exit_tx.send(()).unwrap();
+    })
+    .context("Error setting Ctrl-C handler")?;
+    exit_rx.attach(
+        None,
+        clone!(@weak app => @default-return glib::Continue(false), move |_| {
+            app.quit();
+            glib::Continue(false)
+        }),
+    );
+
+    // Run the application
+    app.run_with_args::<&str>(&[]);
+
+    Ok(())
+}
+
+fn apply_overrides(settings: &mut Settings, args: &Args) {
+    if let Some(server) = &args.server {
+        settings.server = server.clone();
+    }
+    if let Some(name) = &args.name {
+        settings.name = Some(name.clone());
+    }
+
+    let call = &mut settings.call;
+    call.report_stats |= args.report_stats;
+    if let Some(min) = args.min_bitrate {
+        call.video_bitrate.min = min;
+    }
+    if let Some(max) = args.max_bitrate {
+        call.video_bitrate.max = max;
+    }
+    let processing = &mut call.audio_processing;
+    processing.echo_cancel &= !args.no_echo_cancel;
+    processing.noise_suppression &= !args.no_noise_suppression;
+    processing.gain_control &= !args.no_gain_control;
+}
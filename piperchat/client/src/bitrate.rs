@@ -0,0 +1,230 @@
+//! Adapting the video encoder to the network, driven by the RTCP statistics of the call.
+//!
+//! The target bitrate follows additive increase, multiplicative decrease: it grows slowly
+//! while the peer reports (almost) no loss and the round trip time is stable, and drops
+//! sharply once packets get lost or queues start building up. The resolution and framerate
+//! follow the bitrate, so that a low bitrate isn't spent on more pixels than it can carry.
+//!
+//! The statistics are those of the RTCP receiver reports, which every peer sends.
+//! Transport-wide congestion control isn't used: the peer only sends its feedback once both
+//! sides add transport-wide sequence numbers to their packets, which earlier clients don't, and
+//! making use of per-packet feedback takes a delay-based estimator rather than these rules.
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::CallStats;
+
+// Loss above which the bitrate is decreased, and below which it may increase
+const HIGH_LOSS: f64 = 0.1;
+const LOW_LOSS: f64 = 0.02;
+// Round trip time this many times over the lowest one seen, and at least the margin over it,
+// means the link is congested. The margin, in seconds, keeps the jitter of a fast link from
+// counting.
+const RTT_CONGESTION: f64 = 2.0;
+const RTT_MARGIN: f64 = 0.05;
+const DECREASE: f64 = 0.7;
+// Fraction of the maximum bitrate added on each increase
+const INCREASE: f64 = 0.05;
+
+/// Limits of the video bitrate, in bits per second
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct BitrateBounds {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for BitrateBounds {
+    fn default() -> Self {
+        BitrateBounds {
+            min: 150_000,
+            max: 2_000_000,
+        }
+    }
+}
+
+impl BitrateBounds {
+    /// Where adapting starts: halfway between the bounds
+    pub fn start(&self) -> u32 {
+        let min = self.min.min(self.max);
+        min + (self.max - min) / 2
+    }
+}
+
+/// Resolution and framerate the video is scaled to before encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoQuality {
+    pub width: i32,
+    pub height: i32,
+    pub framerate: i32,
+}
+
+impl VideoQuality {
+    /// The quality worth encoding at the given bitrate
+    pub fn for_bitrate(bitrate: u32) -> Self {
+        let (width, height, framerate) = match bitrate {
+            1_200_000.. => (1280, 720, 30),
+            600_000.. => (960, 540, 30),
+            300_000.. => (640, 360, 30),
+            _ => (320, 180, 15),
+        };
+
+        VideoQuality {
+            width,
+            height,
+            framerate,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BitrateController {
+    bounds: BitrateBounds,
+    target: u32,
+    // packets sent and lost at the previous sample
+    previous: Option<(u64, i64)>,
+    min_rtt: Option<f64>,
+}
+
+impl BitrateController {
+    pub fn new(bounds: BitrateBounds) -> Self {
+        let bounds = BitrateBounds {
+            min: bounds.min.min(bounds.max),
+            max: bounds.max,
+        };
+
+        BitrateController {
+            bounds,
+            target: bounds.start(),
+            previous: None,
+            min_rtt: None,
+        }
+    }
+
+    pub fn target(&self) -> u32 {
+        self.target
+    }
+
+    /// Account for a new statistics sample, returning the new target bitrate if it changed
+    pub fn update(&mut self, stats: &CallStats) -> Option<u32> {
+        let current = (stats.packets_sent, stats.packets_lost);
+        let (sent_before, lost_before) = self.previous.replace(current)?;
+        let sent = stats.packets_sent.saturating_sub(sent_before);
+        if sent == 0 {
+            return None;
+        }
+        let loss = (stats.packets_lost - lost_before).max(0) as f64 / sent as f64;
+
+        let congested = match stats.round_trip_time {
+            Some(rtt) => {
+                let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+                self.min_rtt = Some(min_rtt);
+                rtt > min_rtt * RTT_CONGESTION && rtt - min_rtt > RTT_MARGIN
+            }
+            None => false,
+        };
+
+        let target = if loss > HIGH_LOSS || congested {
+            (self.target as f64 * DECREASE) as u32
+        } else if loss < LOW_LOSS {
+            self.target
+                .saturating_add((self.bounds.max as f64 * INCREASE) as u32)
+        } else {
+            self.target
+        };
+        let target = target.clamp(self.bounds.min, self.bounds.max);
+
+        if target == self.target {
+            None
+        } else {
+            self.target = target;
+            Some(target)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(packets_sent: u64, packets_lost: i64) -> CallStats {
+        CallStats {
+            packets_sent,
+            packets_lost,
+            ..CallStats::default()
+        }
+    }
+
+    #[test]
+    fn adapts_within_bounds() {
+        let bounds = BitrateBounds {
+            min: 100_000,
+            max: 1_000_000,
+        };
+        let mut controller = BitrateController::new(bounds);
+        assert_eq!(controller.target(), 550_000);
+
+        // the first sample only sets the baseline
+        assert_eq!(controller.update(&sample(100, 0)), None);
+
+        // heavy loss backs off multiplicatively, down to the minimum
+        assert_eq!(controller.update(&sample(200, 50)), Some(385_000));
+        let mut sent = 200;
+        let mut lost = 50;
+        for _ in 0..20 {
+            sent += 100;
+            lost += 50;
+            controller.update(&sample(sent, lost));
+        }
+        assert_eq!(controller.target(), bounds.min);
+
+        // a clean link grows additively, up to the maximum
+        assert_eq!(controller.update(&sample(sent + 100, lost)), Some(150_000));
+        for _ in 0..40 {
+            sent += 100;
+            controller.update(&sample(sent + 100, lost));
+        }
+        assert_eq!(controller.target(), bounds.max);
+    }
+
+    #[test]
+    fn backs_off_when_round_trip_time_grows() {
+        let mut controller = BitrateController::new(BitrateBounds::default());
+        let target = controller.target();
+        let rtt = |packets_sent, rtt| CallStats {
+            packets_sent,
+            round_trip_time: Some(rtt),
+            ..CallStats::default()
+        };
+
+        controller.update(&rtt(100, 0.05));
+        controller.update(&rtt(200, 0.05));
+        assert!(controller.target() > target);
+        assert!(controller.update(&rtt(300, 0.2)).unwrap() < target);
+    }
+
+    #[test]
+    fn jitter_on_a_fast_link_isnt_congestion() {
+        let mut controller = BitrateController::new(BitrateBounds::default());
+        let target = controller.target();
+        let rtt = |packets_sent, rtt| CallStats {
+            packets_sent,
+            round_trip_time: Some(rtt),
+            ..CallStats::default()
+        };
+
+        controller.update(&rtt(100, 0.0003));
+        for (sample, jitter) in [0.0002, 0.001, 0.0004, 0.0015, 0.0008].iter().enumerate() {
+            let packets_sent = 200 + 100 * sample as u64;
+            controller.update(&rtt(packets_sent, 0.0003 + jitter));
+        }
+        assert!(controller.target() > target);
+    }
+
+    #[test]
+    fn quality_follows_bitrate() {
+        assert_eq!(VideoQuality::for_bitrate(2_000_000).height, 720);
+        assert_eq!(VideoQuality::for_bitrate(400_000).height, 360);
+        assert_eq!(VideoQuality::for_bitrate(100_000).framerate, 15);
+    }
+}
@@ -0,0 +1,133 @@
+//! Automated peer that answers calls on its own, for QA and latency checks.
+//!
+//! The bot is configured with a small TOML file:
+//!
+//! ```toml
+//! server = "ws://localhost:2137"
+//! name = "echo-bot"
+//! # only answer calls from these users, everyone when left out
+//! allow = ["alice"]
+//! # hang up after this many seconds
+//! max_call_duration = 60
+//!
+//! [media]
+//! source = "echo"   # "test", "echo" or { file = "sample.webm" }
+//! sink = "fake"     # "fake" or "devices"
+//! report_stats = true  # send a call quality summary to the server at hangup
+//! video_bitrate = { min = 150000, max = 2000000 }  # range the video bitrate adapts within
+//! opus = { fec = true, dtx = true, bitrate = 24000, audio_type = "voice" }
+//! audio_processing = { echo_cancel = false }  # only applies to the "devices" source
+//! ```
+
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::time::Duration;
+
+use anyhow::Context;
+use async_std::channel::Receiver;
+use async_std::task;
+use futures::future::Fuse;
+use futures::{select, FutureExt, StreamExt};
+use log::info;
+use serde::Deserialize;
+
+use crate::client::{Client, Event};
+use crate::session::{CallConfig, MediaSink, MediaSource};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BotConfig {
+    #[serde(default = "default_server")]
+    pub server: String,
+    pub name: String,
+    /// Names of the users whose calls get answered, everyone's when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Hang up calls lasting longer than this many seconds
+    pub max_call_duration: Option<u64>,
+    #[serde(default = "default_media")]
+    pub media: CallConfig,
+}
+
+fn default_server() -> String {
+    "ws://localhost:2137".to_string()
+}
+
+// Unlike a regular client, a bot has no camera or display by default
+fn default_media() -> CallConfig {
+    CallConfig {
+        source: MediaSource::Echo,
+        sink: MediaSink::Fake,
+        ..CallConfig::default()
+    }
+}
+
+impl BotConfig {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("can't read bot config {}", path.display()))?;
+        let config = toml::from_str(&content)
+            .with_context(|| format!("invalid bot config {}", path.display()))?;
+
+        Ok(config)
+    }
+
+    fn accepts(&self, name: &str) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|allowed| allowed == name)
+    }
+}
+
+type Timer = Fuse<Pin<Box<dyn Future<Output = ()> + Send>>>;
+
+/// Connect to the server the way `config` describes
+pub async fn connect(config: &BotConfig) -> anyhow::Result<(Client, Receiver<Event>)> {
+    let connection = Client::connect(&config.server, &config.name, config.media.clone()).await?;
+    info!("{} connected to {}", config.name, config.server);
+
+    Ok(connection)
+}
+
+/// Answer incoming calls until the connection is closed
+pub async fn run(
+    config: &BotConfig,
+    client: Client,
+    events: Receiver<Event>,
+) -> anyhow::Result<()> {
+    let mut events = events.fuse();
+    let mut hangup_timer: Timer = Fuse::terminated();
+
+    loop {
+        select! {
+            event = events.next() => match event {
+                Some(Event::CallReceived(name)) => {
+                    if config.accepts(&name) {
+                        info!("answering call from {name}");
+                        client.accept().await?;
+                        if let Some(seconds) = config.max_call_duration {
+                            let sleep: Pin<Box<dyn Future<Output = ()> + Send>> =
+                                Box::pin(task::sleep(Duration::from_secs(seconds)));
+                            hangup_timer = sleep.fuse();
+                        }
+                    } else {
+                        info!("rejecting call from {name}");
+                        client.reject().await?;
+                    }
+                }
+                Some(Event::CallEnded) => {
+                    info!("call ended");
+                    hangup_timer = Fuse::terminated();
+                }
+                Some(Event::Disconnected(Some(reason))) => anyhow::bail!("connection lost: {reason}"),
+                Some(event) => info!("{event:?}"),
+                None => break,
+            },
+            _ = &mut hangup_timer => {
+                info!("maximum call duration reached, hanging up");
+                client.hangup().await?;
+            },
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,629 @@
+//! Signalling client, independent of any user interface.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use futures::StreamExt;
+//! use piperchat::client::{Client, Event};
+//! use piperchat::session::CallConfig;
+//!
+//! let (client, mut events) =
+//!     Client::connect("ws://localhost:2137", "alice", CallConfig::default()).await?;
+//! while let Some(event) = events.next().await {
+//!     if let Event::CallReceived(_) = event {
+//!         client.accept().await?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use async_std::channel::{Receiver, Sender};
+use async_std::task;
+use async_tungstenite::async_std::ConnectStream;
+use async_tungstenite::tungstenite::Error;
+use async_tungstenite::WebSocketStream;
+use futures::channel::mpsc;
+use futures::{select, Sink, SinkExt, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use rand::Rng;
+
+use crate::bitrate::BitrateController;
+use crate::message::call::{CallEvent, CallState, Phase};
+use crate::message::*;
+use crate::session::{App, CallConfig, CallSide};
+use crate::stats::CallStats;
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+// How long the end of a call waits for the recording to be finalized
+const RECORDING_TIMEOUT: Duration = Duration::from_secs(5);
+
+type WsMessage = async_tungstenite::tungstenite::Message;
+type PcMessage = crate::message::Message;
+
+/// Everything that happens on the connection, in the order it happened
+#[derive(Debug)]
+pub enum Event {
+    UserlistReceived(Vec<UserInfo>),
+    UserJoined(UserInfo),
+    UserLeft(u32),
+    UserUpdated(UserInfo),
+    CallReceived(String),
+    CallAccepted,
+    CallRejected(String),
+    CallDoNotDisturb(String),
+    CallNoAnswer(String),
+    CallHangup(String),
+    CallEnded,
+    RecordingStarted(PathBuf),
+    RecordingStopped,
+    RecordingFailed(String),
+    PeerRecording(bool),
+    /// Periodic quality statistics of the ongoing call
+    CallStats(CallStats),
+    /// A notice from the server operator
+    Announcement(String),
+    /// The connection is closed, with the reason if it wasn't closed cleanly.
+    /// Always the last event.
+    Disconnected(Option<String>),
+}
+
+#[derive(Debug)]
+enum Command {
+    CallStart(u32, String),
+    CallAccept,
+    CallReject,
+    CallHangup,
+    SetPresence(Presence, String),
+    StartRecording(PathBuf),
+    StopRecording,
+    SetCallConfig(CallConfig),
+}
+
+/// Handle to a connection with the signalling server.
+///
+/// Cloned handles control the same connection. Requests are processed in order by a
+/// background task; their results are reported through the event stream returned by
+/// [`Client::connect`].
+#[derive(Debug, Clone)]
+pub struct Client {
+    command_tx: Sender<Command>,
+    exit_tx: mpsc::UnboundedSender<()>,
+}
+
+impl Client {
+    /// Connect to the signalling server at `server` and register under `name`.
+    /// Calls made through this client use the media described by `config`.
+    pub async fn connect(
+        server: &str,
+        name: &str,
+        config: CallConfig,
+    ) -> anyhow::Result<(Client, Receiver<Event>)> {
+        gst::init()?;
+        let ws = connect(server, name.to_string()).await?;
+
+        let (event_tx, event_rx) = async_std::channel::unbounded();
+        let (command_tx, command_rx) = async_std::channel::unbounded();
+        let (exit_tx, exit_rx) = mpsc::unbounded();
+
+        task::spawn(async move {
+            let result = run(ws, exit_rx, event_tx.clone(), command_rx, config).await;
+            if let Err(err) = &result {
+                error!("connection failed: {err:#}");
+            }
+            let reason = result.err().map(|err| err.to_string());
+            // nobody may be listening anymore
+            let _ = event_tx.send(Event::Disconnected(reason)).await;
+        });
+
+        Ok((
+            Client {
+                command_tx,
+                exit_tx,
+            },
+            event_rx,
+        ))
+    }
+
+    /// Call the user with the given id, `name` is used to refer to them in events
+    pub async fn call(&self, id: u32, name: &str) -> anyhow::Result<()> {
+        self.send(Command::CallStart(id, name.to_string())).await
+    }
+
+    pub async fn accept(&self) -> anyhow::Result<()> {
+        self.send(Command::CallAccept).await
+    }
+
+    pub async fn reject(&self) -> anyhow::Result<()> {
+        self.send(Command::CallReject).await
+    }
+
+    /// End the current call, or cancel it while it's still ringing
+    pub async fn hangup(&self) -> anyhow::Result<()> {
+        self.send(Command::CallHangup).await
+    }
+
+    pub async fn set_presence(&self, presence: Presence, status: &str) -> anyhow::Result<()> {
+        self.send(Command::SetPresence(presence, status.to_string()))
+            .await
+    }
+
+    /// Record the current call to a Matroska file at `path`
+    pub async fn start_recording(&self, path: PathBuf) -> anyhow::Result<()> {
+        self.send(Command::StartRecording(path)).await
+    }
+
+    pub async fn stop_recording(&self) -> anyhow::Result<()> {
+        self.send(Command::StopRecording).await
+    }
+
+    /// Use `config` for the calls made from now on
+    pub async fn set_call_config(&self, config: CallConfig) -> anyhow::Result<()> {
+        self.send(Command::SetCallConfig(config)).await
+    }
+
+    /// Close the connection. The event stream ends shortly afterwards. This doesn't
+    /// block, so it can be used from signal handlers.
+    pub fn disconnect(&self) {
+        // already disconnected otherwise
+        let _ = self.exit_tx.unbounded_send(());
+    }
+
+    async fn send(&self, command: Command) -> anyhow::Result<()> {
+        self.command_tx
+            .send(command)
+            .await
+            .map_err(|_| anyhow!("client is disconnected"))
+    }
+}
+
+// Requests from the UI to the pipeline of an ongoing call
+#[derive(Debug)]
+enum CallControl {
+    StartRecording(PathBuf),
+    StopRecording,
+}
+
+#[derive(Debug)]
+struct Call {
+    handle: task::JoinHandle<anyhow::Result<()>>,
+    app_tx: mpsc::UnboundedSender<WebrtcMsg>,
+    control_tx: mpsc::UnboundedSender<CallControl>,
+    started: Instant,
+    // most recent sample, summarized at hangup
+    stats: Arc<Mutex<Option<CallStats>>>,
+}
+
+impl Call {
+    fn new(
+        gst_tx: mpsc::UnboundedSender<PcMessage>,
+        event_tx: Sender<Event>,
+        callside: CallSide,
+        config: CallConfig,
+        exit_tx: mpsc::UnboundedSender<()>,
+    ) -> anyhow::Result<Self> {
+        let (app_tx, app_rx) = mpsc::unbounded();
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let stats = Arc::new(Mutex::new(None));
+        let last_stats = stats.clone();
+        let handle = task::spawn(async move {
+            let mut bitrate = BitrateController::new(config.video_bitrate);
+            let (gstreamer, gst_bus, gst_rx) = App::new(callside, config)?;
+            let mut gst_bus = gst_bus.fuse();
+            let mut gst_rx = gst_rx.fuse();
+            let mut app_rx = app_rx.fuse();
+            let mut control_rx = control_rx.fuse();
+            let mut stats_timer = async_std::stream::interval(STATS_INTERVAL).fuse();
+            let mut previous: Option<(CallStats, Instant)> = None;
+
+            let result: anyhow::Result<()> = async {
+                loop {
+                    select! {
+                        gst_msg = gst_bus.select_next_some() => {
+                            debug!("pipeline message: {gst_msg:?}");
+                            if let Err(err) = gstreamer.handle_pipeline_message(&gst_msg) {
+                                error!("{err}");
+                                break;
+                            }
+                        }
+                        // send websocket messages emitted by gst and exit if gstreamer exited
+                        ws_msg = gst_rx.next() => {
+                            match ws_msg {
+                                Some(ws_msg) => gst_tx.unbounded_send(PcMessage::Webrtc(ws_msg))?,
+                                None => break
+                            }
+                        }
+                        control = control_rx.select_next_some() => {
+                            let result = match control {
+                                CallControl::StartRecording(path) => {
+                                    gstreamer.start_recording(&path).map(|_| (true, Event::RecordingStarted(path)))
+                                }
+                                CallControl::StopRecording => {
+                                    gstreamer.stop_recording().map(|_| (false, Event::RecordingStopped))
+                                }
+                            };
+                            // a failed recording shouldn't end the call
+                            match result {
+                                Ok((active, event)) => {
+                                    gst_tx.unbounded_send(PcMessage::Recording(RecordingMessage { active }))?;
+                                    event_tx.send(event).await?;
+                                }
+                                Err(err) => {
+                                    error!("{err:#}");
+                                    event_tx.send(Event::RecordingFailed(err.to_string())).await?;
+                                }
+                            }
+                        }
+                        ws_msg = app_rx.next() => {
+                            match ws_msg {
+                                Some(ws_msg) => gstreamer.handle_webrtc_message(ws_msg)?,
+                                None => break,
+                            }
+                        }
+                        _ = stats_timer.select_next_some() => {
+                            match gstreamer.stats().await {
+                                Ok(mut stats) => {
+                                    let now = Instant::now();
+                                    if let Some((previous, taken)) = &previous {
+                                        stats.update_rates(previous, now - *taken);
+                                    }
+                                    debug!("call stats: {stats:?}");
+                                    if let Some(target) = bitrate.update(&stats) {
+                                        debug!("video bitrate adapted to {target}");
+                                        gstreamer.set_video_bitrate(target);
+                                    }
+                                    *last_stats.lock().unwrap() = Some(stats.clone());
+                                    event_tx.send(Event::CallStats(stats.clone())).await?;
+                                    previous = Some((stats, now));
+                                }
+                                Err(err) => warn!("can't get call stats: {err:#}"),
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            .await;
+
+            // however the call ends, the recording file is only playable once EOS made it
+            // through the muxer
+            if let Ok(finished) = gstreamer.stop_recording() {
+                if async_std::future::timeout(RECORDING_TIMEOUT, finished)
+                    .await
+                    .is_err()
+                {
+                    warn!("the recording wasn't finalized in time");
+                }
+                let _ =
+                    gst_tx.unbounded_send(PcMessage::Recording(RecordingMessage { active: false }));
+                let _ = event_tx.send(Event::RecordingStopped).await;
+            }
+
+            info!("running drop on gst task");
+            exit_tx.unbounded_send(())?;
+
+            result
+        });
+
+        Ok(Call {
+            handle,
+            app_tx,
+            control_tx,
+            started: Instant::now(),
+            stats,
+        })
+    }
+
+    // Log how the call went and summarize it for the server if the user opted in
+    fn summary(&self, report: bool) -> Option<PcMessage> {
+        let stats = self.stats.lock().unwrap().clone()?;
+        let summary = stats.summary(self.started.elapsed());
+        info!("call summary: {summary:?}");
+
+        report.then_some(PcMessage::CallStats(summary))
+    }
+
+    // Stop the media, once the recording is finalized if there's one, and summarize the call
+    async fn end(self, report: bool) -> Option<PcMessage> {
+        let summary = self.summary(report);
+        // the task stops once nothing can reach it anymore
+        let Call {
+            handle,
+            app_tx,
+            control_tx,
+            ..
+        } = self;
+        drop((app_tx, control_tx));
+        let output = handle.await;
+        info!("Call terminated. Reason: {output:?}");
+
+        summary
+    }
+}
+
+/// Connect to the signalling server at `server` and register under the given name
+async fn connect(server: &str, name: String) -> anyhow::Result<WebSocketStream<ConnectStream>> {
+    // Connect to the given server
+    let (mut ws, _) = async_tungstenite::async_std::connect_async(server).await?;
+
+    // Say HELLO to the server and see if it replies with HELLO
+    // the server turns away ids that are taken, which is unlikely in a range this large
+    let id = rand::thread_rng().gen_range(10..u32::MAX);
+    info!("connected to {server}, registering id {id}");
+    let connect_message = serde_json::to_string(&PcMessage::Connect(ConnectMessage { name, id }))?;
+    ws.send(WsMessage::Text(connect_message)).await?;
+
+    let msg = ws
+        .next()
+        .await
+        .ok_or_else(|| anyhow!("didn't receive anything"))??;
+    let response = if let WsMessage::Text(msg) = msg {
+        msg
+    } else {
+        bail!("bad message");
+    };
+    let response: PcMessage = serde_json::from_str(&response)?;
+    info!("{:?}", &response);
+    match response {
+        PcMessage::ConnectResponse(ConnectResponse::Accept) => (),
+        PcMessage::ConnectResponse(ConnectResponse::Reject(reason)) => {
+            bail!("server rejected the connection. Reason: {reason}");
+        }
+        msg => bail!("Expected connection accept, received: {msg:?}"),
+    }
+
+    Ok(ws)
+}
+
+// `config` with the ICE servers the server handed out in place of the configured ones
+fn with_ice(config: &CallConfig, server_ice: &Option<IceServersMessage>) -> CallConfig {
+    let mut config = config.clone();
+    if let Some(ice) = server_ice {
+        if ice.stun.is_some() {
+            config.ice.stun = ice.stun.clone();
+        }
+        if ice.turn.is_some() {
+            config.ice.turn = ice.turn.clone();
+        }
+    }
+    config
+}
+
+async fn run(
+    ws: impl Sink<WsMessage, Error = Error> + Stream<Item = Result<WsMessage, Error>>,
+    mut exit_rx: mpsc::UnboundedReceiver<()>,
+    event_tx: Sender<Event>,
+    mut command_rx: Receiver<Command>,
+    mut config: CallConfig,
+) -> Result<(), anyhow::Error> {
+    // Split the websocket into the Sink and Stream
+    let (mut ws_sink, ws_stream) = ws.split();
+
+    // Fuse the Stream, required for the select macro
+    let mut ws_stream = ws_stream.fuse();
+
+    let (gst_tx, mut gst_rx) = mpsc::unbounded::<PcMessage>();
+
+    let (gst_exit_tx, mut gst_exit_rx) = mpsc::unbounded();
+
+    let mut state: CallState<String> = CallState::Idle;
+    // media of the call, present while in a call
+    let mut call: Option<Call> = None;
+    // ICE servers handed out by the server, used instead of the configured ones
+    let mut server_ice: Option<IceServersMessage> = None;
+
+    // And now let's start our message loop
+    loop {
+        // statistics of a call that just ended, sent after the hangup
+        let mut summary = None;
+        let ws_msg: Option<PcMessage> = select! {
+            // Handle the WebSocket messages here
+            ws_msg = ws_stream.select_next_some() => {
+                info!("received: {ws_msg:?}");
+                match ws_msg? {
+                    WsMessage::Close(_) => {
+                        info!("server closed the connection");
+                        break
+                    },
+                    WsMessage::Text(text) => {
+                        let message: PcMessage = serde_json::from_str(&text)?;
+
+                        if let PcMessage::UserList(userlist) = message {
+                            event_tx.send(Event::UserlistReceived(userlist.users)).await?;
+                        } else if let PcMessage::UserJoined(user) = message {
+                            event_tx.send(Event::UserJoined(user)).await?;
+                        } else if let PcMessage::UserLeft(id) = message {
+                            event_tx.send(Event::UserLeft(id)).await?;
+                        } else if let PcMessage::UserUpdated(user) = message {
+                            event_tx.send(Event::UserUpdated(user)).await?;
+                        } else if let PcMessage::IceServers(ice) = message {
+                            info!("using the server's ICE servers: {ice:?}");
+                            server_ice = Some(ice);
+                        } else if let PcMessage::Announcement(announcement) = message {
+                            event_tx.send(Event::Announcement(announcement.text)).await?;
+                        } else if let PcMessage::Kicked(kicked) = message {
+                            match kicked.reason.as_str() {
+                                "" => bail!("removed by the server operator"),
+                                reason => bail!("removed by the server operator: {reason}"),
+                            }
+                        } else if let PcMessage::ProtocolError(error) = message {
+                            bail!("disconnected by the server: {}", error.reason);
+                        } else {
+                            let phase = state.phase();
+                            match message {
+                                PcMessage::CallReceived(CallReceivedMessage { name }) => {
+                                    match state.handle(CallEvent::Incoming(name.clone())) {
+                                        Ok(_) => {
+                                            info!("Receiving a call from {name}");
+                                            event_tx.send(Event::CallReceived(name)).await?;
+                                        }
+                                        Err(err) => warn!("call from {name} received: {err}"),
+                                    }
+                                },
+                                // peer can accept or reject
+                                PcMessage::CallResponse(CallResponseMessage::Accept) => {
+                                    match state.handle(CallEvent::Accepted) {
+                                        Ok(_) => {
+                                            event_tx.send(Event::CallAccepted).await?;
+                                            call = Some(Call::new(gst_tx.clone(), event_tx.clone(), CallSide::Caller, with_ice(&config, &server_ice), gst_exit_tx.clone())?);
+                                        }
+                                        Err(err) => warn!("{err}"),
+                                    }
+                                },
+                                PcMessage::CallResponse(response) => {
+                                    match state.handle(CallEvent::Rejected) {
+                                        Ok(Some(name)) => {
+                                            let event = match response {
+                                                CallResponseMessage::DoNotDisturb => Event::CallDoNotDisturb(name),
+                                                CallResponseMessage::NoAnswer => Event::CallNoAnswer(name),
+                                                _ => Event::CallRejected(name),
+                                            };
+                                            event_tx.send(event).await?;
+                                        }
+                                        Ok(None) => (),
+                                        Err(err) => warn!("{err}"),
+                                    }
+                                },
+                                // peer hung up or the server cancelled the call
+                                PcMessage::CallHangup | PcMessage::CallCancelled => {
+                                    match state.handle(CallEvent::PeerHangup) {
+                                        Ok(Some(name)) if phase == Phase::Received => {
+                                            event_tx.send(Event::CallHangup(name)).await?;
+                                        }
+                                        Ok(_) => {
+                                            if let Some(ended) = call.take() {
+                                                summary = ended.end(config.report_stats).await;
+                                            }
+                                            event_tx.send(Event::CallEnded).await?;
+                                        }
+                                        Err(err) => warn!("{err}"),
+                                    }
+                                },
+                                PcMessage::Webrtc(webrtc) => match &call {
+                                    Some(call) => call.app_tx.unbounded_send(webrtc).unwrap(),
+                                    None => warn!("received webrtc message while not in call"),
+                                },
+                                PcMessage::Recording(recording) => {
+                                    if phase == Phase::InCall {
+                                        event_tx.send(Event::PeerRecording(recording.active)).await?;
+                                    }
+                                },
+                                _ => {
+                                    warn!("received wrong message: {message:?}");
+                                }
+                            }
+                        }
+
+                        None
+                    },
+                    WsMessage::Frame(_) => unreachable!(),
+                    _ => None
+                }
+            },
+            // Handle WebSocket messages we created asynchronously to send them out now
+            ws_msg = gst_rx.select_next_some() => Some(ws_msg),
+
+            // the client was asked to disconnect
+            _ = exit_rx.select_next_some() => break,
+
+            command = command_rx.select_next_some() => {
+                match command {
+                    Command::CallStart(id, name) => {
+                        info!("calling {id}");
+
+                        // Join the given session
+                        state.handle(CallEvent::Dial(name))
+                            .map(|_| PcMessage::Call(CallMessage { peer: id }))
+                            .map_err(|err| warn!("{err}"))
+                            .ok()
+                    },
+                    Command::CallAccept => {
+                        match state.handle(CallEvent::Accept) {
+                            Ok(_) => {
+                                call = Some(Call::new(gst_tx.clone(), event_tx.clone(), CallSide::Callee, with_ice(&config, &server_ice), gst_exit_tx.clone())?);
+                                Some(PcMessage::CallResponse(CallResponseMessage::Accept))
+                            }
+                            Err(err) => {
+                                warn!("{err}");
+                                None
+                            }
+                        }
+                    },
+                    Command::CallReject => {
+                        state.handle(CallEvent::Reject)
+                            .map(|_| PcMessage::CallResponse(CallResponseMessage::Reject))
+                            .map_err(|err| warn!("{err}"))
+                            .ok()
+                    },
+                    Command::CallHangup => {
+                        if let Some(ended) = call.take() {
+                            summary = ended.end(config.report_stats).await;
+                        }
+                        state.handle(CallEvent::Hangup)
+                            .map(|_| PcMessage::CallHangup)
+                            .map_err(|err| warn!("{err}"))
+                            .ok()
+                    },
+                    Command::SetPresence(presence, status) => {
+                        Some(PcMessage::SetPresence(PresenceMessage { presence, status }))
+                    },
+                    Command::StartRecording(path) => {
+                        if let Some(call) = &call {
+                            call.control_tx.unbounded_send(CallControl::StartRecording(path))?;
+                        }
+                        None
+                    },
+                    Command::StopRecording => {
+                        if let Some(call) = &call {
+                            call.control_tx.unbounded_send(CallControl::StopRecording)?;
+                        }
+                        None
+                    },
+                    Command::SetCallConfig(new_config) => {
+                        config = new_config;
+                        None
+                    },
+                }
+            }
+
+            _ = gst_exit_rx.select_next_some() => {
+                if let Some(ended) = call.take() {
+                    summary = ended.end(config.report_stats).await;
+                    event_tx.send(Event::CallEnded).await?;
+                    state.handle(CallEvent::Hangup)?;
+                    Some(PcMessage::CallHangup)
+                } else {
+                    error!("Gstreamer exit received while not in call");
+                    None
+                }
+            }
+
+            // Once we're done, break the loop and return
+            complete => break,
+        };
+
+        // media messages queued in the meantime go first, e.g. the end of a recording before
+        // the hangup
+        let queued: Vec<PcMessage> =
+            std::iter::from_fn(|| gst_rx.try_next().ok().flatten()).collect();
+
+        // If there's a message to send out, do so now
+        for ws_msg in queued.into_iter().chain(ws_msg).chain(summary) {
+            info!("sending: {ws_msg:?}");
+            let message = WsMessage::Text(serde_json::to_string(&ws_msg)?);
+            ws_sink.send(message).await?;
+        }
+    }
+
+    // the recording of a call still going on gets finalized
+    if let Some(ended) = call.take() {
+        ended.end(false).await;
+    }
+
+    ws_sink.close().await?;
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! Local-only pipeline for checking the camera, microphone and speaker before a call, using
+//! the same device selection as the call pipeline.
+
+use std::rc::Rc;
+
+use anyhow::Context;
+use gst::glib;
+use gst::prelude::*;
+use gtk::gdk;
+use log::warn;
+
+use crate::devices::{DeviceKind, DeviceSelection};
+
+// Lowest level shown by the meter, in dB
+const SILENCE: f64 = -60.0;
+
+pub enum DeviceTestEvent {
+    /// A new camera frame
+    Frame(gdk::Texture),
+    /// Microphone peak level, between 0 (silence) and 1
+    Level(f64),
+    Error(String),
+}
+
+#[derive(Debug)]
+pub struct DeviceTest {
+    pipeline: gst::Pipeline,
+    watch: Option<glib::SourceId>,
+}
+
+impl DeviceTest {
+    /// Start the test with the selected devices. Has to be called from the main context, which
+    /// is where `on_event` gets called.
+    pub fn start(
+        devices: &DeviceSelection,
+        on_event: impl Fn(DeviceTestEvent) + 'static,
+    ) -> anyhow::Result<Self> {
+        let pipeline = gst::parse_launch(
+            "videoconvert name=camera_input ! videoscale ! \
+            video/x-raw,format=RGBA,width=640,pixel-aspect-ratio=1/1 ! \
+            appsink name=preview max-buffers=1 drop=true sync=false \
+            audioconvert name=microphone_input ! level interval=50000000 ! fakesink sync=false \
+            audiotestsrc is-live=true freq=440 ! volume name=tone volume=0.3 mute=true ! \
+            audioconvert ! audioresample name=speaker_output",
+        )?
+        .downcast::<gst::Pipeline>()
+        .expect("not a pipeline");
+
+        for (kind, name) in [
+            (DeviceKind::Camera, "camera_input"),
+            (DeviceKind::Microphone, "microphone_input"),
+            (DeviceKind::Speaker, "speaker_output"),
+        ] {
+            let device = devices.element(kind)?;
+            pipeline.add(&device)?;
+            let element = pipeline.by_name(name).expect("can't find device element");
+            let linked = match kind {
+                DeviceKind::Speaker => element.link(&device),
+                _ => device.link(&element),
+            };
+            linked.with_context(|| format!("can't link the {}", kind.label().to_lowercase()))?;
+        }
+
+        let on_event = Rc::new(on_event);
+
+        // Frames arrive on a streaming thread, the textures are made on the main context
+        let (frame_tx, frame_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        let preview = pipeline
+            .by_name("preview")
+            .expect("can't find preview")
+            .downcast::<gst_app::AppSink>()
+            .expect("not an appsink");
+        preview.set_callbacks(
+            gst_app::AppSinkCallbacks::builder()
+                .new_sample(move |appsink| {
+                    let sample = appsink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
+                    frame_tx
+                        .send(sample)
+                        .map_err(|_| gst::FlowError::Flushing)?;
+                    Ok(gst::FlowSuccess::Ok)
+                })
+                .build(),
+        );
+        frame_rx.attach(
+            None,
+            glib::clone!(@strong on_event => move |sample| {
+                match texture(&sample) {
+                    Some(texture) => on_event(DeviceTestEvent::Frame(texture)),
+                    None => warn!("can't show camera frame {sample:?}"),
+                }
+                glib::Continue(true)
+            }),
+        );
+
+        let watch = pipeline
+            .bus()
+            .expect("pipeline without a bus")
+            .add_watch_local(move |_bus, message| {
+                use gst::MessageView;
+
+                match message.view() {
+                    MessageView::Element(element) => {
+                        if let Some(level) = element.structure().and_then(peak_level) {
+                            on_event(DeviceTestEvent::Level(level));
+                        }
+                    }
+                    MessageView::Error(err) => {
+                        on_event(DeviceTestEvent::Error(err.error().to_string()));
+                    }
+                    _ => (),
+                }
+                glib::Continue(true)
+            })?;
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("can't start the device test")?;
+
+        Ok(DeviceTest {
+            pipeline,
+            watch: Some(watch),
+        })
+    }
+
+    /// Play a sine tone on the speaker
+    pub fn set_tone(&self, playing: bool) {
+        let tone = self.pipeline.by_name("tone").expect("can't find tone");
+        tone.set_property("mute", !playing);
+    }
+}
+
+impl Drop for DeviceTest {
+    fn drop(&mut self) {
+        if let Some(watch) = self.watch.take() {
+            watch.remove();
+        }
+        let _ = self.pipeline.set_state(gst::State::Null);
+    }
+}
+
+// Highest peak of all channels, scaled to 0..1
+fn peak_level(structure: &gst::StructureRef) -> Option<f64> {
+    if structure.name() != "level" {
+        return None;
+    }
+
+    let peaks = structure.get::<glib::ValueArray>("peak").ok()?;
+    let peak = peaks
+        .iter()
+        .filter_map(|value| value.get::<f64>().ok())
+        .fold(SILENCE, f64::max);
+
+    Some(((peak - SILENCE) / -SILENCE).clamp(0.0, 1.0))
+}
+
+fn texture(sample: &gst::Sample) -> Option<gdk::Texture> {
+    let info = gst_video::VideoInfo::from_caps(sample.caps()?).ok()?;
+    let buffer = sample.buffer()?.map_readable().ok()?;
+    let bytes = glib::Bytes::from(buffer.as_slice());
+
+    let texture = gdk::MemoryTexture::new(
+        info.width() as i32,
+        info.height() as i32,
+        gdk::MemoryFormat::R8g8b8a8,
+        &bytes,
+        info.stride()[0] as usize,
+    );
+    Some(texture.upcast())
+}
@@ -0,0 +1,151 @@
+//! Cameras, microphones and speakers found by GStreamer's device monitor, and the ones the
+//! user picked among them.
+
+use anyhow::Context;
+use gst::glib;
+use gst::prelude::*;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Camera,
+    Microphone,
+    Speaker,
+}
+
+impl DeviceKind {
+    pub const ALL: [DeviceKind; 3] = [
+        DeviceKind::Camera,
+        DeviceKind::Microphone,
+        DeviceKind::Speaker,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DeviceKind::Camera => "Camera",
+            DeviceKind::Microphone => "Microphone",
+            DeviceKind::Speaker => "Speaker",
+        }
+    }
+
+    fn class(self) -> &'static str {
+        match self {
+            DeviceKind::Camera => "Video/Source",
+            DeviceKind::Microphone => "Audio/Source",
+            DeviceKind::Speaker => "Audio/Sink",
+        }
+    }
+
+    // Element used when nothing is selected, or the selected device isn't plugged in
+    fn fallback(self) -> &'static str {
+        match self {
+            DeviceKind::Camera => "v4l2src",
+            DeviceKind::Microphone => "autoaudiosrc",
+            DeviceKind::Speaker => "autoaudiosink",
+        }
+    }
+}
+
+/// Devices picked by the user, by their display name. `None` leaves the choice to GStreamer.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct DeviceSelection {
+    pub camera: Option<String>,
+    pub microphone: Option<String>,
+    pub speaker: Option<String>,
+}
+
+impl DeviceSelection {
+    pub fn get(&self, kind: DeviceKind) -> Option<&str> {
+        match kind {
+            DeviceKind::Camera => self.camera.as_deref(),
+            DeviceKind::Microphone => self.microphone.as_deref(),
+            DeviceKind::Speaker => self.speaker.as_deref(),
+        }
+    }
+
+    pub fn set(&mut self, kind: DeviceKind, name: Option<String>) {
+        let slot = match kind {
+            DeviceKind::Camera => &mut self.camera,
+            DeviceKind::Microphone => &mut self.microphone,
+            DeviceKind::Speaker => &mut self.speaker,
+        };
+        *slot = name;
+    }
+
+    /// Create the element for the selected device of `kind`
+    pub fn element(&self, kind: DeviceKind) -> anyhow::Result<gst::Element> {
+        if let Some(name) = self.get(kind) {
+            let devices = Devices::start()?;
+            match devices.find(kind, name) {
+                Some(device) => {
+                    return device
+                        .create_element(None)
+                        .with_context(|| format!("can't open {name}"))
+                }
+                None => warn!("{} {name:?} is not plugged in", kind.label().to_lowercase()),
+            }
+        }
+
+        gst::ElementFactory::make(kind.fallback())
+            .build()
+            .with_context(|| format!("can't create {}", kind.fallback()))
+    }
+}
+
+/// A running device monitor, keeping track of devices as they're plugged in and removed
+#[derive(Debug)]
+pub struct Devices {
+    monitor: gst::DeviceMonitor,
+}
+
+impl Devices {
+    pub fn start() -> anyhow::Result<Self> {
+        let monitor = gst::DeviceMonitor::new();
+        for kind in DeviceKind::ALL {
+            monitor.add_filter(Some(kind.class()), None);
+        }
+        monitor.start().context("can't start the device monitor")?;
+
+        Ok(Devices { monitor })
+    }
+
+    /// Display names of the devices of `kind` that are plugged in
+    pub fn names(&self, kind: DeviceKind) -> Vec<String> {
+        self.monitor
+            .devices()
+            .into_iter()
+            .filter(|device| device.has_classes(kind.class()))
+            .map(|device| device.display_name().to_string())
+            .collect()
+    }
+
+    fn find(&self, kind: DeviceKind, name: &str) -> Option<gst::Device> {
+        self.monitor
+            .devices()
+            .into_iter()
+            .find(|device| device.has_classes(kind.class()) && device.display_name() == name)
+    }
+
+    /// Call `on_change` from the main context whenever a device is plugged in or removed
+    pub fn watch<F: Fn() + 'static>(&self, on_change: F) -> anyhow::Result<glib::SourceId> {
+        let bus = self.monitor.bus();
+        let source = bus.add_watch_local(move |_bus, message| {
+            use gst::MessageView;
+
+            if let MessageView::DeviceAdded(_) | MessageView::DeviceRemoved(_) = message.view() {
+                on_change();
+            }
+            glib::Continue(true)
+        })?;
+
+        Ok(source)
+    }
+}
+
+impl Drop for Devices {
+    fn drop(&mut self) {
+        self.monitor.stop();
+    }
+}
@@ -1,2 +1,3 @@
 pub mod contact_object;
+pub mod preferences;
 pub mod window;
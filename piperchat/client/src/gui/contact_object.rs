@@ -0,0 +1,36 @@
+mod imp;
+
+use glib::Object;
+use gtk::glib;
+use gtk::prelude::*;
+
+use crate::message::{Presence, UserInfo};
+
+glib::wrapper! {
+    pub struct ContactObject(ObjectSubclass<imp::ContactObject>);
+}
+
+impl ContactObject {
+    pub fn new(user: UserInfo) -> Self {
+        Object::builder()
+            .property("id", user.id)
+            .property("name", user.name)
+            .property("presence", user.presence.as_str())
+            .property("status", user.status)
+            .build()
+    }
+
+    pub fn update(&self, user: UserInfo) {
+        self.set_property("name", user.name);
+        self.set_property("presence", user.presence.as_str());
+        self.set_property("status", user.status);
+    }
+}
+
+#[derive(Default)]
+pub struct ContactData {
+    pub id: u32,
+    pub name: String,
+    pub presence: Presence,
+    pub status: String,
+}
@@ -30,6 +30,8 @@ impl ObjectImpl for ContactObject {
             vec![
                 ParamSpecUInt::builder("id").build(),
                 ParamSpecString::builder("name").build(),
+                ParamSpecString::builder("presence").build(),
+                ParamSpecString::builder("status").build(),
             ]
         });
         PROPERTIES.as_ref()
@@ -47,6 +49,20 @@ impl ObjectImpl for ContactObject {
                     .expect("The value needs to be of type `String`.");
                 self.data.borrow_mut().name = input_value;
             }
+            "presence" => {
+                let input_value: String = value
+                    .get()
+                    .expect("The value needs to be of type `String`.");
+                self.data.borrow_mut().presence = input_value
+                    .parse()
+                    .expect("The value needs to be a valid presence.");
+            }
+            "status" => {
+                let input_value = value
+                    .get()
+                    .expect("The value needs to be of type `String`.");
+                self.data.borrow_mut().status = input_value;
+            }
             _ => unimplemented!(),
         }
     }
@@ -55,6 +71,8 @@ impl ObjectImpl for ContactObject {
         match pspec.name() {
             "id" => self.data.borrow().id.to_value(),
             "name" => self.data.borrow().name.to_value(),
+            "presence" => self.data.borrow().presence.as_str().to_value(),
+            "status" => self.data.borrow().status.to_value(),
             _ => unimplemented!(),
         }
     }
@@ -0,0 +1,218 @@
+//! Preferences window for the server, the codecs and the devices used in calls. The device
+//! lists are kept up to date as devices are plugged in and removed.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use adw::prelude::*;
+use gtk::glib::{self, clone};
+use log::warn;
+
+use crate::devices::{DeviceKind, Devices};
+use crate::settings::Settings;
+
+const AUTOMATIC: &str = "Automatic";
+
+struct Preferences {
+    devices: Option<Devices>,
+    rows: Vec<(DeviceKind, adw::ComboRow)>,
+    settings: RefCell<Settings>,
+    on_change: Box<dyn Fn(&Settings)>,
+    watch: Cell<Option<glib::SourceId>>,
+    // set while the rows are rebuilt, so that doesn't count as the user picking a device
+    updating: Cell<bool>,
+}
+
+impl Preferences {
+    fn refresh(&self) {
+        let devices = match &self.devices {
+            Some(devices) => devices,
+            None => return,
+        };
+
+        self.updating.set(true);
+        let selection = &self.settings.borrow().call.devices;
+        for (kind, row) in &self.rows {
+            let mut names = devices.names(*kind);
+            let selected = selection.get(*kind);
+
+            // a device that's gone stays selected, it's used again once it's back
+            let missing = selected.filter(|selected| !names.iter().any(|name| name == selected));
+            if let Some(missing) = missing {
+                names.push(missing.to_string());
+            }
+            row.set_subtitle(if missing.is_some() {
+                "Not plugged in"
+            } else {
+                ""
+            });
+
+            let model = gtk::StringList::new(&[AUTOMATIC]);
+            for name in &names {
+                model.append(name);
+            }
+            row.set_model(Some(&model));
+            let position = selected
+                .and_then(|selected| names.iter().position(|name| name == selected))
+                .map_or(0, |position| position + 1);
+            row.set_selected(position as u32);
+        }
+        self.updating.set(false);
+    }
+
+    fn select(&self, kind: DeviceKind, row: &adw::ComboRow) {
+        if self.updating.get() {
+            return;
+        }
+
+        let name = match row.selected() {
+            0 => None,
+            _ => row
+                .selected_item()
+                .and_then(|item| item.downcast::<gtk::StringObject>().ok())
+                .map(|item| item.string().to_string()),
+        };
+        self.change(|settings| settings.call.devices.set(kind, name));
+    }
+
+    fn change(&self, change: impl FnOnce(&mut Settings)) {
+        change(&mut self.settings.borrow_mut());
+        (self.on_change)(&self.settings.borrow());
+    }
+}
+
+fn switch_row(title: &str, subtitle: &str, active: bool) -> (adw::ActionRow, gtk::Switch) {
+    let switch = gtk::Switch::builder()
+        .active(active)
+        .valign(gtk::Align::Center)
+        .build();
+    let row = adw::ActionRow::builder()
+        .title(title)
+        .subtitle(subtitle)
+        .activatable_widget(&switch)
+        .build();
+    row.add_suffix(&switch);
+
+    (row, switch)
+}
+
+/// Show the preferences over `parent`. `on_change` is called with all of the settings whenever
+/// the user changes one of them.
+pub fn show(
+    parent: &impl IsA<gtk::Window>,
+    settings: Settings,
+    on_change: impl Fn(&Settings) + 'static,
+) {
+    let window = adw::PreferencesWindow::builder()
+        .transient_for(parent)
+        .modal(true)
+        .search_enabled(false)
+        .build();
+    let page = adw::PreferencesPage::new();
+    window.add(&page);
+
+    let connection = adw::PreferencesGroup::builder()
+        .title("Connection")
+        .description("Used when logging in the next time.")
+        .build();
+    let server = adw::EntryRow::builder()
+        .title("Server")
+        .text(&settings.server)
+        .show_apply_button(true)
+        .build();
+    connection.add(&server);
+    page.add(&connection);
+
+    let audio = adw::PreferencesGroup::builder()
+        .title("Audio")
+        .description("Used from the next call on.")
+        .build();
+    let (fec_row, fec) = switch_row(
+        "Error Correction",
+        "Recover lost audio at the cost of some bandwidth",
+        settings.call.opus.fec,
+    );
+    let (dtx_row, dtx) = switch_row(
+        "Discontinuous Transmission",
+        "Send almost nothing while you're quiet",
+        settings.call.opus.dtx,
+    );
+    audio.add(&fec_row);
+    audio.add(&dtx_row);
+    page.add(&audio);
+
+    let group = adw::PreferencesGroup::builder()
+        .title("Devices")
+        .description("Used from the next call on.")
+        .build();
+    page.add(&group);
+
+    let devices = match Devices::start() {
+        Ok(devices) => Some(devices),
+        Err(err) => {
+            warn!("{err:#}");
+            group.set_description(Some(
+                "Devices can't be listed, they're picked automatically.",
+            ));
+            None
+        }
+    };
+    let rows = DeviceKind::ALL
+        .into_iter()
+        .map(|kind| {
+            let row = adw::ComboRow::builder()
+                .title(kind.label())
+                .sensitive(devices.is_some())
+                .build();
+            group.add(&row);
+            (kind, row)
+        })
+        .collect();
+
+    let preferences = Rc::new(Preferences {
+        devices,
+        rows,
+        settings: RefCell::new(settings),
+        on_change: Box::new(on_change),
+        watch: Cell::new(None),
+        updating: Cell::new(false),
+    });
+    preferences.refresh();
+
+    server.connect_apply(clone!(@weak preferences => move |row| {
+        let server = row.text().to_string();
+        preferences.change(|settings| settings.server = server);
+    }));
+    fec.connect_active_notify(clone!(@weak preferences => move |switch| {
+        let active = switch.is_active();
+        preferences.change(|settings| settings.call.opus.fec = active);
+    }));
+    dtx.connect_active_notify(clone!(@weak preferences => move |switch| {
+        let active = switch.is_active();
+        preferences.change(|settings| settings.call.opus.dtx = active);
+    }));
+
+    for (kind, row) in &preferences.rows {
+        let kind = *kind;
+        row.connect_selected_notify(clone!(@weak preferences => move |row| {
+            preferences.select(kind, row);
+        }));
+    }
+
+    if let Some(devices) = &preferences.devices {
+        match devices.watch(clone!(@weak preferences => move || preferences.refresh())) {
+            Ok(watch) => preferences.watch.set(Some(watch)),
+            Err(err) => warn!("can't watch for devices: {err:#}"),
+        }
+    }
+
+    // Keeps the preferences alive for as long as the window is open
+    window.connect_close_request(move |_window| {
+        if let Some(watch) = preferences.watch.take() {
+            watch.remove();
+        }
+        gtk::Inhibit(false)
+    });
+
+    window.present();
+}
@@ -0,0 +1,649 @@
+mod imp;
+
+use crate::device_test::{DeviceTest, DeviceTestEvent};
+use crate::history::{CallDirection, CallOutcome, CallRecord};
+use crate::message::{Presence, UserInfo};
+use crate::session::AudioProcessing;
+use crate::settings::Settings;
+use crate::stats::{format_bitrate, CallStats};
+use crate::{GuiEvent, VideoPreference};
+use adw::subclass::prelude::*;
+use adw::{prelude::*, ActionRow, ResponseAppearance};
+use async_std::channel::Sender;
+use futures::{select, FutureExt};
+use gtk::glib::{self, clone, Object};
+use gtk::{gio, Align, Button, Entry, Image, NoSelection};
+
+use super::contact_object::ContactObject;
+use super::preferences;
+
+const AUDIO_PROCESSING_ACTIONS: [&str; 3] = ["echo_cancel", "noise_suppression", "gain_control"];
+
+glib::wrapper! {
+    pub struct Window(ObjectSubclass<imp::Window>)
+        @extends adw::ApplicationWindow, gtk::Window, gtk::Widget,
+        @implements gio::ActionGroup, gio::ActionMap, gtk::Accessible, gtk::Buildable,
+                    gtk::ConstraintTarget, gtk::Native, gtk::Root, gtk::ShortcutManager;
+}
+
+impl Window {
+    pub fn new(app: &adw::Application, gui_tx: Sender<GuiEvent>) -> Self {
+        // Create new window
+        let window: Window = Object::new(&[("application", app)]);
+        window.imp().window_data.borrow_mut().gui_tx = Some(gui_tx);
+
+        // Activated from the missed call notifications, so it has to live on the application
+        let action_call_back =
+            gio::SimpleAction::new("call-back", Some(&String::static_variant_type()));
+        action_call_back.connect_activate(clone!(@weak window => move |_, parameter| {
+            if let Some(name) = parameter.and_then(|parameter| parameter.get::<String>()) {
+                window.present();
+                window.call_back(&name);
+            }
+        }));
+        app.add_action(&action_call_back);
+
+        window
+    }
+
+    fn setup_actions(&self) {
+        // Create action to create new collection and add to action group "win"
+        let action_set_user = gio::SimpleAction::new("set_user", None);
+        action_set_user.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.imp().stack.set_visible_child_name("main");
+        }));
+        self.add_action(&action_set_user);
+
+        let action_show_history = gio::SimpleAction::new("show_history", None);
+        action_show_history.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.imp().stack.set_visible_child_name("history");
+        }));
+        self.add_action(&action_show_history);
+
+        let action_show_contacts = gio::SimpleAction::new("show_contacts", None);
+        action_show_contacts.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.imp().stack.set_visible_child_name("main");
+        }));
+        self.add_action(&action_show_contacts);
+
+        let action_show_device_test = gio::SimpleAction::new("show_device_test", None);
+        action_show_device_test.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.imp().stack.set_visible_child_name("test");
+        }));
+        self.add_action(&action_show_device_test);
+
+        // Stateful action holding the presence picked from the main menu
+        let action_presence = gio::SimpleAction::new_stateful(
+            "presence",
+            Some(&String::static_variant_type()),
+            &Presence::Available.as_str().to_variant(),
+        );
+        action_presence.connect_activate(clone!(@weak self as window => move |action, parameter| {
+            let parameter = parameter
+                .and_then(|parameter| parameter.get::<String>())
+                .expect("The presence parameter needs to be of type `String`.");
+            action.set_state(&parameter.to_variant());
+            window.send_presence();
+        }));
+        self.add_action(&action_presence);
+
+        // The state follows the pipeline, it only changes once recording actually starts or stops
+        let action_record = gio::SimpleAction::new_stateful("record", None, &false.to_variant());
+        action_record.connect_activate(clone!(@weak self as window => move |action, _| {
+            let recording = action
+                .state()
+                .and_then(|state| state.get::<bool>())
+                .unwrap_or(false);
+            window
+                .sender()
+                .send_blocking(GuiEvent::RecordingToggled(!recording))
+                .unwrap();
+        }));
+        self.add_action(&action_record);
+
+        let action_hangup = gio::SimpleAction::new("hangup", None);
+        action_hangup.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.hangup_call();
+        }));
+        self.add_action(&action_hangup);
+
+        let action_set_status = gio::SimpleAction::new("set_status", None);
+        action_set_status.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.show_status_dialog();
+        }));
+        self.add_action(&action_set_status);
+
+        let action_preferences = gio::SimpleAction::new("preferences", None);
+        action_preferences.connect_activate(clone!(@weak self as window => move |_, _| {
+            window.show_preferences();
+        }));
+        self.add_action(&action_preferences);
+
+        // Audio processing toggles from the main menu, used from the next call on
+        for name in AUDIO_PROCESSING_ACTIONS {
+            let action = gio::SimpleAction::new_stateful(name, None, &true.to_variant());
+            action.connect_activate(clone!(@weak self as window => move |action, _| {
+                let enabled = action
+                    .state()
+                    .and_then(|state| state.get::<bool>())
+                    .unwrap_or(false);
+                action.set_state(&(!enabled).to_variant());
+                window
+                    .sender()
+                    .send_blocking(GuiEvent::AudioProcessingChanged(window.audio_processing()))
+                    .unwrap();
+            }));
+            self.add_action(&action);
+        }
+    }
+
+    fn audio_processing(&self) -> AudioProcessing {
+        let enabled = |name| {
+            self.action_state(name)
+                .and_then(|state| state.get::<bool>())
+                .unwrap_or(false)
+        };
+
+        AudioProcessing {
+            echo_cancel: enabled("echo_cancel"),
+            noise_suppression: enabled("noise_suppression"),
+            gain_control: enabled("gain_control"),
+        }
+    }
+
+    pub fn set_audio_processing(&self, processing: AudioProcessing) {
+        let states = [
+            processing.echo_cancel,
+            processing.noise_suppression,
+            processing.gain_control,
+        ];
+        for (name, enabled) in AUDIO_PROCESSING_ACTIONS.into_iter().zip(states) {
+            if let Some(action) = self
+                .lookup_action(name)
+                .and_then(|action| action.downcast::<gio::SimpleAction>().ok())
+            {
+                action.set_state(&enabled.to_variant());
+            }
+        }
+    }
+
+    fn presence(&self) -> Presence {
+        self.action_state("presence")
+            .and_then(|state| state.get::<String>())
+            .and_then(|state| state.parse().ok())
+            .unwrap_or_default()
+    }
+
+    fn send_presence(&self) {
+        let status = self.imp().window_data.borrow().status.clone();
+        self.sender()
+            .send_blocking(GuiEvent::PresenceChanged(self.presence(), status))
+            .unwrap();
+    }
+
+    fn show_status_dialog(&self) {
+        let entry = Entry::builder()
+            .text(&self.imp().window_data.borrow().status)
+            .placeholder_text("What are you up to?")
+            .activates_default(true)
+            .build();
+
+        let dialog = adw::MessageDialog::new(
+            Some(self),
+            Some("Set status message"),
+            Some("The status message is shown to other users next to your name."),
+        );
+        dialog.set_extra_child(Some(&entry));
+        dialog.add_responses(&[("cancel", "Cancel"), ("set", "Set")]);
+        dialog.set_response_appearance("set", ResponseAppearance::Suggested);
+        dialog.set_default_response(Some("set"));
+
+        dialog.run_async(
+            None,
+            clone!(@weak self as window, @weak entry => move |_obj, response| {
+                if response == "set" {
+                    window.imp().window_data.borrow_mut().status = entry.text().to_string();
+                    window.send_presence();
+                }
+            }),
+        );
+    }
+
+    fn show_preferences(&self) {
+        let settings = self.imp().window_data.borrow().settings.clone();
+        preferences::show(
+            self,
+            settings,
+            clone!(@weak self as window => move |settings| {
+                window.imp().window_data.borrow_mut().settings = settings.clone();
+                window
+                    .sender()
+                    .send_blocking(GuiEvent::PreferencesChanged(settings.clone()))
+                    .unwrap();
+            }),
+        );
+    }
+
+    /// Settings shown in the preferences, the name is prefilled on the login page
+    pub fn set_settings(&self, settings: Settings) {
+        if let Some(name) = &settings.name {
+            self.imp().stack_name_entry.set_text(name);
+        }
+        self.imp().window_data.borrow_mut().settings = settings;
+    }
+
+    fn setup_device_test(&self) {
+        // The test pipeline only runs while its page is shown
+        self.imp().stack.connect_visible_child_name_notify(
+            clone!(@weak self as window => move |stack| {
+                if stack.visible_child_name().as_deref() == Some("test") {
+                    window.start_device_test();
+                } else {
+                    window.imp().window_data.borrow_mut().device_test = None;
+                }
+            }),
+        );
+
+        self.imp()
+            .test_tone
+            .connect_toggled(clone!(@weak self as window => move |button| {
+                if let Some(test) = &window.imp().window_data.borrow().device_test {
+                    test.set_tone(button.is_active());
+                }
+            }));
+    }
+
+    fn start_device_test(&self) {
+        let imp = self.imp();
+        imp.test_preview.set_paintable(None::<&gtk::gdk::Paintable>);
+        imp.test_level.set_value(0.0);
+        imp.test_tone.set_active(false);
+        imp.test_error.set_visible(false);
+
+        let devices = imp.window_data.borrow().settings.call.devices.clone();
+        let test = DeviceTest::start(
+            &devices,
+            clone!(@weak self as window => move |event| {
+                let imp = window.imp();
+                match event {
+                    DeviceTestEvent::Frame(texture) => imp.test_preview.set_paintable(Some(&texture)),
+                    DeviceTestEvent::Level(level) => imp.test_level.set_value(level),
+                    DeviceTestEvent::Error(message) => {
+                        imp.test_error.set_label(&message);
+                        imp.test_error.set_visible(true);
+                    }
+                }
+            }),
+        );
+
+        match test {
+            Ok(test) => imp.window_data.borrow_mut().device_test = Some(test),
+            Err(err) => {
+                imp.test_error.set_label(&format!("{err:#}"));
+                imp.test_error.set_visible(true);
+            }
+        }
+    }
+
+    fn contacts(&self) -> gio::ListStore {
+        // Get state
+        self.imp()
+            .window_data
+            .borrow()
+            .contacts
+            .clone()
+            .expect("Could not get current tasks.")
+    }
+
+    fn history(&self) -> gio::ListStore {
+        self.imp()
+            .window_data
+            .borrow()
+            .history
+            .clone()
+            .expect("Could not get call history.")
+    }
+
+    fn sender(&self) -> Sender<GuiEvent> {
+        self.imp()
+            .window_data
+            .borrow()
+            .gui_tx
+            .as_ref()
+            .unwrap()
+            .clone()
+    }
+
+    fn setup_contacts(&self) {
+        // Create new model
+        let model = gio::ListStore::new(ContactObject::static_type());
+
+        // Get state and set model
+        self.imp().window_data.borrow_mut().contacts = Some(model);
+
+        // Wrap model with selection and pass it to the list view
+        let selection_model = NoSelection::new(Some(&self.contacts()));
+        self.imp().contacts_list.bind_model(Some(&selection_model),
+    clone!(@weak self as window => @default-panic, move |obj| {
+                let task_object = obj.downcast_ref().expect("The object should be of type `ContactObject`.");
+                let row = window.create_contact_row(task_object);
+                row.upcast()
+            }));
+    }
+
+    fn setup_history(&self) {
+        // Records are plain Rust structs, so they are wrapped in `BoxedAnyObject`s
+        let model = gio::ListStore::new(glib::BoxedAnyObject::static_type());
+        self.imp().window_data.borrow_mut().history = Some(model);
+
+        let selection_model = NoSelection::new(Some(&self.history()));
+        self.imp().history_list.bind_model(
+            Some(&selection_model),
+            clone!(@weak self as window => @default-panic, move |obj| {
+                let record = obj
+                    .downcast_ref::<glib::BoxedAnyObject>()
+                    .expect("The object should be of type `BoxedAnyObject`.");
+                let row = window.create_history_row(&record.borrow::<CallRecord>());
+                row.upcast()
+            }),
+        );
+    }
+
+    fn create_history_row(&self, record: &CallRecord) -> adw::ActionRow {
+        let icon_name = match (record.direction, record.outcome) {
+            (CallDirection::Incoming, CallOutcome::Answered) => "call-incoming-symbolic",
+            (CallDirection::Incoming, _) => "call-missed-symbolic",
+            (CallDirection::Outgoing, _) => "call-outgoing-symbolic",
+        };
+        let icon = Image::from_icon_name(icon_name);
+        if record.direction == CallDirection::Incoming && record.outcome != CallOutcome::Answered {
+            icon.add_css_class("error");
+        }
+
+        let started = glib::DateTime::from_unix_local(record.started as i64)
+            .and_then(|started| started.format("%x %R"))
+            .map(|started| started.to_string())
+            .unwrap_or_default();
+        let subtitle = if record.outcome == CallOutcome::Answered {
+            format!(
+                "{} · {started} · {}:{:02}",
+                record.description(),
+                record.duration / 60,
+                record.duration % 60
+            )
+        } else {
+            format!("{} · {started}", record.description())
+        };
+
+        let call_back_button = Button::builder()
+            .icon_name("call-start-symbolic")
+            .tooltip_text("Call back")
+            .valign(Align::Center)
+            .css_classes(vec!["flat".into()])
+            .build();
+        let peer = record.peer.clone();
+        call_back_button.connect_clicked(clone!(@weak self as window => move |_button| {
+            window.call_back(&peer);
+        }));
+
+        let row = ActionRow::builder()
+            .title(&record.peer)
+            .subtitle(&subtitle)
+            .build();
+        row.add_prefix(&icon);
+        row.add_suffix(&call_back_button);
+
+        row
+    }
+
+    fn create_contact_row(&self, contact_object: &ContactObject) -> adw::ActionRow {
+        let call_button = Button::builder()
+            .icon_name("call-start-symbolic")
+            .valign(Align::Center)
+            .css_classes(vec!["flat".into()])
+            .build();
+
+        call_button.connect_clicked(
+            clone!(@weak contact_object, @weak self as window => move |_button| {
+                let id = contact_object.property::<u32>("id");
+                let name = contact_object.property::<String>("name");
+                println!("calling {name}");
+                window.sender().send_blocking(GuiEvent::CallStart(id, name)).unwrap();
+
+            }),
+        );
+
+        let presence_icon = Image::new();
+
+        // Create row
+        let row = ActionRow::builder().build();
+        row.add_prefix(&presence_icon);
+        row.add_suffix(&call_button);
+
+        // Bind properties
+        contact_object
+            .bind_property("name", &row, "title")
+            .flags(glib::BindingFlags::SYNC_CREATE)
+            .build();
+        contact_object
+            .bind_property("status", &row, "subtitle")
+            .flags(glib::BindingFlags::SYNC_CREATE)
+            .build();
+        contact_object
+            .bind_property("presence", &presence_icon, "icon-name")
+            .transform_to(|_, presence: String| {
+                let icon = match presence.parse().unwrap_or_default() {
+                    Presence::Available => "user-available-symbolic",
+                    Presence::Busy => "user-busy-symbolic",
+                    Presence::DoNotDisturb => "action-unavailable-symbolic",
+                };
+                Some(icon)
+            })
+            .flags(glib::BindingFlags::SYNC_CREATE)
+            .build();
+        contact_object
+            .bind_property("presence", &presence_icon, "tooltip-text")
+            .transform_to(|_, presence: String| {
+                let tooltip = match presence.parse().unwrap_or_default() {
+                    Presence::Available => "Available",
+                    Presence::Busy => "Busy",
+                    Presence::DoNotDisturb => "Do not disturb",
+                };
+                Some(tooltip)
+            })
+            .flags(glib::BindingFlags::SYNC_CREATE)
+            .build();
+
+        // Return row
+        row
+    }
+
+    // Bring the model in line with a full user list snapshot, reusing the objects of users that are
+    // already displayed
+    pub fn set_contacts(&self, contacts: Vec<UserInfo>) {
+        let contact_list = self.contacts();
+
+        let mut position = 0;
+        while position < contact_list.n_items() {
+            let id = self.contact_at(position).property::<u32>("id");
+            if contacts.iter().any(|user| user.id == id) {
+                position += 1;
+            } else {
+                contact_list.remove(position);
+            }
+        }
+
+        for user in contacts {
+            match self.find_contact(user.id) {
+                Some((_, contact)) => contact.update(user),
+                None => contact_list.append(&ContactObject::new(user)),
+            }
+        }
+    }
+
+    pub fn add_contact(&self, user: UserInfo) {
+        match self.find_contact(user.id) {
+            Some((_, contact)) => contact.update(user),
+            None => self.contacts().append(&ContactObject::new(user)),
+        }
+    }
+
+    pub fn remove_contact(&self, id: u32) {
+        if let Some((position, _)) = self.find_contact(id) {
+            self.contacts().remove(position);
+        }
+    }
+
+    pub fn update_contact(&self, user: UserInfo) {
+        if let Some((_, contact)) = self.find_contact(user.id) {
+            contact.update(user);
+        }
+    }
+
+    pub fn set_history(&self, records: &[CallRecord]) {
+        let history = self.history();
+        history.remove_all();
+        // newest calls go on top
+        for record in records.iter().rev() {
+            history.append(&glib::BoxedAnyObject::new(record.clone()));
+        }
+    }
+
+    pub fn add_history_record(&self, record: CallRecord) {
+        self.history().insert(0, &glib::BoxedAnyObject::new(record));
+    }
+
+    pub fn notify_missed_call(&self, record: &CallRecord) {
+        let app = match self.application() {
+            Some(app) => app,
+            None => return,
+        };
+
+        let notification = gio::Notification::new("Missed call");
+        notification.set_body(Some(&format!("You missed a call from {}.", record.peer)));
+        notification.add_button_with_target_value(
+            "Call back",
+            "app.call-back",
+            Some(&record.peer.to_variant()),
+        );
+        app.send_notification(Some("missed-call"), &notification);
+    }
+
+    // Call a user by name, as ids don't outlive a single session
+    pub fn call_back(&self, name: &str) {
+        let contact = (0..self.contacts().n_items())
+            .map(|position| self.contact_at(position))
+            .find(|contact| contact.property::<String>("name") == name);
+
+        match contact {
+            Some(contact) => {
+                self.imp().stack.set_visible_child_name("main");
+                let id = contact.property::<u32>("id");
+                self.sender()
+                    .send_blocking(GuiEvent::CallStart(id, name.to_string()))
+                    .unwrap();
+            }
+            None => {
+                let dialog = adw::MessageDialog::new(
+                    Some(self),
+                    Some("User unavailable"),
+                    Some(&format!("{name} is not connected right now.")),
+                );
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+        }
+    }
+
+    fn contact_at(&self, position: u32) -> ContactObject {
+        self.contacts()
+            .item(position)
+            .and_then(|object| object.downcast::<ContactObject>().ok())
+            .expect("The object should be of type `ContactObject`.")
+    }
+
+    fn find_contact(&self, id: u32) -> Option<(u32, ContactObject)> {
+        (0..self.contacts().n_items())
+            .map(|position| (position, self.contact_at(position)))
+            .find(|(_, contact)| contact.property::<u32>("id") == id)
+    }
+
+    pub fn accept_call(&self) {
+        self.sender().send_blocking(GuiEvent::CallAccepted).unwrap();
+    }
+
+    pub fn reject_call(&self) {
+        self.sender().send_blocking(GuiEvent::CallRejected).unwrap();
+    }
+
+    pub fn hangup_call(&self) {
+        self.sender().send_blocking(GuiEvent::CallHangup).unwrap();
+    }
+
+    pub fn show_call(&self, peer: &str) {
+        self.imp().call_status.set_title(peer);
+        self.set_recording(false);
+        self.set_peer_recording(false);
+        self.set_call_stats(&CallStats::default());
+        self.imp().stack.set_visible_child_name("call");
+    }
+
+    pub fn hide_call(&self) {
+        self.imp().stack.set_visible_child_name("main");
+    }
+
+    // Go back to the name entry, e.g. after losing the connection
+    pub fn show_login(&self) {
+        self.set_contacts(Vec::new());
+        self.imp().stack.set_visible_child_name("placeholder");
+    }
+
+    pub fn set_call_stats(&self, stats: &CallStats) {
+        let imp = self.imp();
+        imp.stats_sent
+            .set_label(&format_bitrate(stats.send_bitrate));
+        imp.stats_received
+            .set_label(&format_bitrate(stats.receive_bitrate));
+        imp.stats_loss
+            .set_label(&format!("{:.1} %", stats.loss() * 100.0));
+        imp.stats_jitter
+            .set_label(&format!("{:.0} ms", stats.jitter * 1000.0));
+        let rtt = match stats.round_trip_time {
+            Some(rtt) => format!("{:.0} ms", rtt * 1000.0),
+            None => "–".to_string(),
+        };
+        imp.stats_rtt.set_label(&rtt);
+    }
+
+    pub fn set_recording(&self, recording: bool) {
+        if let Some(action) = self
+            .lookup_action("record")
+            .and_then(|action| action.downcast::<gio::SimpleAction>().ok())
+        {
+            action.set_state(&recording.to_variant());
+        }
+        self.imp().recording_indicator.set_visible(recording);
+    }
+
+    pub fn set_peer_recording(&self, recording: bool) {
+        self.imp().peer_recording_label.set_visible(recording);
+    }
+
+    /// Show a notice from the server operator at the bottom of the window
+    pub fn show_announcement(&self, text: &str) {
+        let imp = self.imp();
+        imp.announcement_label.set_label(text);
+        imp.announcement_bar.set_revealed(true);
+    }
+}
+
+#[derive(Default)]
+pub struct WindowData {
+    pub contacts: Option<gio::ListStore>,
+    pub history: Option<gio::ListStore>,
+    pub username: String,
+    pub status: String,
+    pub gui_tx: Option<Sender<GuiEvent>>,
+    pub settings: Settings,
+    pub device_test: Option<DeviceTest>,
+}
@@ -1,8 +1,8 @@
 use std::{cell::RefCell, rc::Rc};
 
-use adw::{prelude::*, subclass::prelude::*, EntryRow};
+use adw::{prelude::*, subclass::prelude::*, EntryRow, StatusPage};
 use glib::subclass::InitializingObject;
-use gtk::{glib, CompositeTemplate, Entry, ListBox, Stack};
+use gtk::{glib, CompositeTemplate, Entry, Label, ListBox, Stack};
 
 use crate::GuiEvent;
 
@@ -18,6 +18,36 @@ pub struct Window {
     pub stack_name_entry: TemplateChild<Entry>,
     #[template_child]
     pub contacts_list: TemplateChild<ListBox>,
+    #[template_child]
+    pub history_list: TemplateChild<ListBox>,
+    #[template_child]
+    pub call_status: TemplateChild<StatusPage>,
+    #[template_child]
+    pub recording_indicator: TemplateChild<gtk::Box>,
+    #[template_child]
+    pub peer_recording_label: TemplateChild<Label>,
+    #[template_child]
+    pub stats_sent: TemplateChild<Label>,
+    #[template_child]
+    pub stats_received: TemplateChild<Label>,
+    #[template_child]
+    pub stats_loss: TemplateChild<Label>,
+    #[template_child]
+    pub stats_jitter: TemplateChild<Label>,
+    #[template_child]
+    pub stats_rtt: TemplateChild<Label>,
+    #[template_child]
+    pub test_preview: TemplateChild<gtk::Picture>,
+    #[template_child]
+    pub test_level: TemplateChild<gtk::LevelBar>,
+    #[template_child]
+    pub test_tone: TemplateChild<gtk::ToggleButton>,
+    #[template_child]
+    pub test_error: TemplateChild<Label>,
+    #[template_child]
+    pub announcement_bar: TemplateChild<gtk::InfoBar>,
+    #[template_child]
+    pub announcement_label: TemplateChild<Label>,
 
     pub window_data: Rc<RefCell<WindowData>>,
 }
@@ -54,6 +84,12 @@ impl Window {
             .unwrap();
         self.window_data.borrow_mut().username = username.clone();
     }
+
+    // the close button hides the announcement until the next one
+    #[template_callback]
+    fn handle_announcement_response(&self) {
+        self.announcement_bar.set_revealed(false);
+    }
 }
 
 // Trait shared by all GObjects
@@ -65,6 +101,8 @@ impl ObjectImpl for Window {
         let obj = self.obj();
         obj.setup_actions();
         obj.setup_contacts();
+        obj.setup_history();
+        obj.setup_device_test();
     }
 }
 
@@ -0,0 +1,217 @@
+//! Line-based control of the client from stdin, for running without a desktop session.
+//!
+//! Commands:
+//! - `users` - list connected users
+//! - `call <name or id>` - call a user
+//! - `accept`, `reject` - answer an incoming call
+//! - `hangup` - end or cancel the current call
+//! - `presence <available|busy|donotdisturb> [status message]` - change presence
+//! - `stats` - show the quality of the current call
+//! - `quit` - disconnect and exit
+
+use std::collections::BTreeMap;
+
+use async_std::channel::Receiver;
+use async_std::io::BufReader;
+use futures::{select, AsyncBufReadExt, StreamExt};
+use log::warn;
+
+use crate::client::{Client, Event};
+use crate::message::{Presence, UserInfo};
+use crate::stats::{format_bitrate, CallStats};
+
+#[derive(Debug, Default, Clone)]
+pub struct HeadlessOptions {
+    /// Call the user with this name or id as soon as they are online
+    pub call: Option<String>,
+    /// Accept all incoming calls without asking
+    pub auto_accept: bool,
+}
+
+struct Headless {
+    client: Client,
+    options: HeadlessOptions,
+    users: BTreeMap<u32, UserInfo>,
+    stats: Option<CallStats>,
+}
+
+/// Print the client's events to stdout and drive it with commands read from stdin.
+/// Returns once the connection is closed.
+pub async fn run(
+    client: Client,
+    events: Receiver<Event>,
+    options: HeadlessOptions,
+) -> anyhow::Result<()> {
+    let mut headless = Headless {
+        client,
+        options,
+        users: BTreeMap::new(),
+        stats: None,
+    };
+
+    let mut events = events.fuse();
+    let mut lines = BufReader::new(async_std::io::stdin()).lines().fuse();
+
+    loop {
+        select! {
+            event = events.next() => match event {
+                Some(event) => headless.handle_event(event).await?,
+                None => break,
+            },
+            // stdin reaching EOF just stops the input, the client keeps running
+            line = lines.select_next_some() => headless.handle_line(line?.trim()).await?,
+        }
+    }
+
+    Ok(())
+}
+
+impl Headless {
+    async fn handle_event(&mut self, event: Event) -> anyhow::Result<()> {
+        match event {
+            Event::UserlistReceived(users) => {
+                self.users = users.into_iter().map(|user| (user.id, user)).collect();
+                self.print_users();
+                self.call_requested_user().await?;
+            }
+            Event::UserJoined(user) => {
+                println!("joined: {}", format_user(&user));
+                self.users.insert(user.id, user);
+                self.call_requested_user().await?;
+            }
+            Event::UserLeft(id) => {
+                if let Some(user) = self.users.remove(&id) {
+                    println!("left: {} ({})", user.name, user.id);
+                }
+            }
+            Event::UserUpdated(user) => {
+                println!("updated: {}", format_user(&user));
+                self.users.insert(user.id, user);
+            }
+            Event::CallReceived(name) => {
+                if self.options.auto_accept {
+                    println!("incoming call from {name}, accepting");
+                    self.client.accept().await?;
+                } else {
+                    println!("incoming call from {name}, type `accept` or `reject`");
+                }
+            }
+            Event::CallAccepted => println!("call accepted"),
+            Event::CallRejected(name) => println!("{name} rejected the call"),
+            Event::CallDoNotDisturb(name) => println!("{name} doesn't want to be disturbed"),
+            Event::CallNoAnswer(name) => println!("{name} didn't answer"),
+            Event::CallHangup(name) => println!("{name} hung up"),
+            Event::CallEnded => {
+                self.stats = None;
+                println!("call ended");
+            }
+            Event::RecordingStarted(path) => println!("recording to {}", path.display()),
+            Event::RecordingStopped => println!("recording stopped"),
+            Event::RecordingFailed(reason) => println!("recording failed: {reason}"),
+            Event::PeerRecording(true) => println!("peer started recording"),
+            Event::PeerRecording(false) => println!("peer stopped recording"),
+            Event::CallStats(stats) => self.stats = Some(stats),
+            Event::Announcement(text) => println!("announcement: {text}"),
+            Event::Disconnected(None) => println!("disconnected"),
+            Event::Disconnected(Some(reason)) => println!("disconnected: {reason}"),
+        }
+
+        Ok(())
+    }
+
+    async fn handle_line(&mut self, line: &str) -> anyhow::Result<()> {
+        let (command, argument) = match line.split_once(' ') {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (line, ""),
+        };
+
+        match command {
+            "" => (),
+            "users" => self.print_users(),
+            "stats" => match &self.stats {
+                Some(stats) => print_stats(stats),
+                None => println!("not in a call"),
+            },
+            "call" => match self.find_user(argument) {
+                Some(user) => self.client.call(user.id, &user.name).await?,
+                None => println!("no such user: {argument}"),
+            },
+            "accept" => self.client.accept().await?,
+            "reject" => self.client.reject().await?,
+            "hangup" => self.client.hangup().await?,
+            "presence" => {
+                let (presence, status) = argument.split_once(' ').unwrap_or((argument, ""));
+                match presence.parse::<Presence>() {
+                    Ok(presence) => self.client.set_presence(presence, status).await?,
+                    Err(err) => println!("{err}"),
+                }
+            }
+            "quit" => self.client.disconnect(),
+            command => {
+                warn!("unknown command: {command}");
+                println!("commands: users, call <name|id>, accept, reject, hangup, presence <presence> [status], stats, quit");
+            }
+        }
+
+        Ok(())
+    }
+
+    // place the call requested on the command line once the user shows up
+    async fn call_requested_user(&mut self) -> anyhow::Result<()> {
+        let user = match self
+            .options
+            .call
+            .as_deref()
+            .and_then(|peer| self.find_user(peer))
+        {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        println!("calling {}", user.name);
+        let (id, name) = (user.id, user.name.clone());
+        self.options.call = None;
+        self.client.call(id, &name).await?;
+
+        Ok(())
+    }
+
+    fn find_user(&self, peer: &str) -> Option<&UserInfo> {
+        match peer.parse::<u32>() {
+            Ok(id) => self.users.get(&id),
+            Err(_) => self.users.values().find(|user| user.name == peer),
+        }
+    }
+
+    fn print_users(&self) {
+        println!("users:");
+        for user in self.users.values() {
+            println!("- {}", format_user(user));
+        }
+    }
+}
+
+fn format_user(user: &UserInfo) -> String {
+    if user.status.is_empty() {
+        format!("{} ({}) {}", user.name, user.id, user.presence.as_str())
+    } else {
+        format!(
+            "{} ({}) {}: {}",
+            user.name,
+            user.id,
+            user.presence.as_str(),
+            user.status
+        )
+    }
+}
+
+fn print_stats(stats: &CallStats) {
+    println!("sending: {}", format_bitrate(stats.send_bitrate));
+    println!("receiving: {}", format_bitrate(stats.receive_bitrate));
+    println!("packet loss: {:.1} %", stats.loss() * 100.0);
+    println!("jitter: {:.0} ms", stats.jitter * 1000.0);
+    match stats.round_trip_time {
+        Some(rtt) => println!("round trip: {:.0} ms", rtt * 1000.0),
+        None => println!("round trip: unknown"),
+    }
+}
@@ -0,0 +1,151 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallDirection {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallOutcome {
+    Answered,
+    Missed,
+    Rejected,
+}
+
+/// A single entry in the call history
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallRecord {
+    pub peer: String,
+    pub direction: CallDirection,
+    pub outcome: CallOutcome,
+    /// Unix timestamp (in seconds) of the moment the call was placed or received
+    pub started: u64,
+    /// How long the call lasted after it was answered, in seconds
+    pub duration: u64,
+}
+
+impl CallRecord {
+    pub fn description(&self) -> &'static str {
+        match (self.direction, self.outcome) {
+            (CallDirection::Incoming, CallOutcome::Answered) => "Incoming call",
+            (CallDirection::Incoming, CallOutcome::Missed) => "Missed call",
+            (CallDirection::Incoming, CallOutcome::Rejected) => "Rejected call",
+            (CallDirection::Outgoing, CallOutcome::Answered) => "Outgoing call",
+            (CallDirection::Outgoing, CallOutcome::Missed) => "Unanswered call",
+            (CallDirection::Outgoing, CallOutcome::Rejected) => "Declined call",
+        }
+    }
+}
+
+/// A call that is ringing or in progress and will become a `CallRecord` once it ends
+#[derive(Debug)]
+pub struct ActiveCall {
+    peer: String,
+    direction: CallDirection,
+    started: SystemTime,
+    answered: Option<SystemTime>,
+}
+
+impl ActiveCall {
+    pub fn new(peer: String, direction: CallDirection) -> Self {
+        ActiveCall {
+            peer,
+            direction,
+            started: SystemTime::now(),
+            answered: None,
+        }
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    pub fn answer(&mut self) {
+        self.answered = Some(SystemTime::now());
+    }
+
+    /// Finish the call. Calls that were answered are always recorded as such, otherwise
+    /// `unanswered` tells why the call didn't take place.
+    pub fn finish(self, unanswered: CallOutcome) -> CallRecord {
+        let (outcome, duration) = match self.answered {
+            Some(answered) => (
+                CallOutcome::Answered,
+                answered.elapsed().unwrap_or(Duration::ZERO),
+            ),
+            None => (unanswered, Duration::ZERO),
+        };
+
+        CallRecord {
+            peer: self.peer,
+            direction: self.direction,
+            outcome,
+            started: self
+                .started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs(),
+            duration: duration.as_secs(),
+        }
+    }
+}
+
+/// Call history persisted as a JSON file in the user data directory
+#[derive(Debug)]
+pub struct CallLog {
+    path: PathBuf,
+    records: Vec<CallRecord>,
+}
+
+impl CallLog {
+    pub fn default_path() -> PathBuf {
+        glib::user_data_dir()
+            .join("piperchat")
+            .join("call-history.json")
+    }
+
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        CallLog {
+            path: path.as_ref().to_path_buf(),
+            records: Vec::new(),
+        }
+    }
+
+    /// Load the call log from `path`. A missing file results in an empty log.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let records = match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("invalid call history file {}", path.display()))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(CallLog { path, records })
+    }
+
+    pub fn records(&self) -> &[CallRecord] {
+        &self.records
+    }
+
+    /// Append a record and write the whole log back to disk
+    pub fn push(&mut self, record: CallRecord) -> anyhow::Result<()> {
+        self.records.push(record);
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.records)?)
+            .with_context(|| format!("can't write call history to {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,495 @@
+pub const APP_ID: &str = "eu.mguzik.piperchat";
+
+pub mod bitrate;
+pub mod bot;
+pub mod client;
+pub mod device_test;
+pub mod devices;
+pub mod gui;
+pub mod headless;
+pub mod history;
+pub mod opus;
+pub use piperchat_protocol as message;
+pub mod session;
+pub mod settings;
+pub mod stats;
+
+use gtk::glib::{self, MainContext};
+pub use message::Message;
+use message::*;
+
+use adw::prelude::MessageDialogExtManual;
+use adw::traits::MessageDialogExt;
+use adw::{MessageDialog, ResponseAppearance};
+
+use async_std::channel::{Receiver, Sender};
+use futures::{select, Future, StreamExt};
+use gtk::prelude::*;
+use log::{error, info, warn};
+use std::path::PathBuf;
+
+use client::{Client, Event};
+use gui::window::Window;
+use history::{ActiveCall, CallDirection, CallLog, CallOutcome};
+use session::AudioProcessing;
+use settings::Settings;
+
+#[derive(Debug)]
+pub enum GuiEvent {
+    CallStart(u32, String),
+    CallAccepted,
+    CallRejected,
+    CallHangup,
+    NameEntered(String),
+    PresenceChanged(Presence, String),
+    RecordingToggled(bool),
+    AudioProcessingChanged(AudioProcessing),
+    /// The server, devices and codecs from the preferences window
+    PreferencesChanged(Settings),
+}
+
+#[derive(Debug)]
+pub enum VideoPreference {
+    Enabled,
+    Disabled,
+}
+
+/// Build the main window. The connection to the server is made once the user picks a name.
+/// Changes made in the GUI are saved to the settings file at `settings_path`.
+pub fn build_ui(app: &adw::Application, settings: Settings, settings_path: PathBuf) {
+    let (gui_tx, gui_rx) = async_std::channel::unbounded::<GuiEvent>();
+    let (network_tx, network_rx) = async_std::channel::unbounded::<Event>();
+
+    // Create a new custom window and show it
+    let window = Window::new(app, gui_tx);
+    window.set_title(Some("Piperchat"));
+    window.present();
+
+    let call_log = CallLog::load(CallLog::default_path()).unwrap_or_else(|err| {
+        warn!("can't load call history: {err:#}");
+        CallLog::new(CallLog::default_path())
+    });
+    window.set_history(call_log.records());
+    window.set_audio_processing(settings.call.audio_processing);
+    window.set_settings(settings.clone());
+
+    let handler = EventHandler {
+        gui_rx,
+        network_rx,
+        network_tx,
+        settings,
+        settings_path,
+        client: None,
+        window,
+        current_dialog: None,
+        call_log,
+        active_call: None,
+    };
+    handler.start();
+
+    info!("UI built");
+}
+
+// Recordings go to the user's videos directory, named after the peer and the current time.
+// The peer picks their own name, so anything in it that could leave the directory is replaced.
+fn recording_path(peer: &str) -> PathBuf {
+    let peer: String = peer
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    let directory =
+        glib::user_special_dir(glib::UserDirectory::Videos).unwrap_or_else(glib::home_dir);
+    let timestamp = glib::DateTime::now_local()
+        .and_then(|now| now.format("%Y-%m-%d-%H%M%S"))
+        .map(|timestamp| timestamp.to_string())
+        .unwrap_or_default();
+
+    directory.join(format!("piperchat-{peer}-{timestamp}.mkv"))
+}
+
+struct EventHandler {
+    gui_rx: Receiver<GuiEvent>,
+    network_rx: Receiver<Event>,
+    // events of the client are forwarded here once connected
+    network_tx: Sender<Event>,
+    settings: Settings,
+    settings_path: PathBuf,
+    client: Option<Client>,
+    current_dialog: Option<MessageDialog>,
+    window: Window,
+    call_log: CallLog,
+    active_call: Option<ActiveCall>,
+}
+
+impl EventHandler {
+    fn start(mut self) {
+        let event_handler = async move {
+            loop {
+                select! {
+                    network_event = self.network_rx.next() => {
+                        match network_event {
+                            Some(event) => self.handle_network_event(event).await,
+                            None => break
+                        }
+                    },
+                    gui_event = self.gui_rx.next() => {
+                        match gui_event {
+                            Some(event) => self.handle_gui_event(event).await,
+                            None => break
+                        }
+                    }
+                }
+            }
+        };
+
+        MainContext::default().spawn_local(event_handler);
+    }
+
+    // Move the current call to the call history
+    fn finish_call(&mut self, unanswered: CallOutcome) {
+        let call = match self.active_call.take() {
+            Some(call) => call,
+            None => return,
+        };
+
+        let record = call.finish(unanswered);
+        if record.direction == CallDirection::Incoming && record.outcome == CallOutcome::Missed {
+            self.window.notify_missed_call(&record);
+        }
+        if let Err(err) = self.call_log.push(record.clone()) {
+            error!("can't save call history: {err:#}");
+        }
+        self.window.add_history_record(record);
+    }
+    async fn connect(&mut self, name: &str) {
+        match Client::connect(&self.settings.server, name, self.settings.call.clone()).await {
+            Ok((client, events)) => {
+                let network_tx = self.network_tx.clone();
+                MainContext::default().spawn_local(async move {
+                    let mut events = events;
+                    while let Some(event) = events.next().await {
+                        if network_tx.send(event).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                self.client = Some(client);
+            }
+            Err(err) => {
+                error!("can't connect to {}: {err:#}", self.settings.server);
+                self.window.show_login();
+
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some("Can't connect"),
+                    Some(&format!("Connecting to the server failed: {err}")),
+                );
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+        }
+    }
+
+    // Send a request through the client, failures only get logged as the client reports
+    // a lost connection through its events
+    async fn request<F, Fut>(&self, request: F)
+    where
+        F: FnOnce(Client) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => {
+                warn!("not connected to the server");
+                return;
+            }
+        };
+
+        if let Err(err) = request(client).await {
+            error!("{err:#}");
+        }
+    }
+
+    // Write settings changed in the GUI to the settings file
+    fn save_settings(&self, change: impl FnOnce(&mut Settings)) {
+        if let Err(err) = Settings::update(&self.settings_path, change) {
+            error!("can't save settings: {err:#}");
+        }
+    }
+
+    // Settings changed in the GUI apply from the next call on
+    async fn update_call_config(&self) {
+        // otherwise they're picked up when connecting
+        if self.client.is_some() {
+            let config = self.settings.call.clone();
+            self.request(|client| async move { client.set_call_config(config).await })
+                .await;
+        }
+    }
+
+    async fn handle_network_event(&mut self, event: Event) {
+        info!("received network event: {event:?}");
+        match event {
+            Event::UserlistReceived(userlist) => {
+                self.window.set_contacts(userlist);
+            }
+            Event::UserJoined(user) => {
+                self.window.add_contact(user);
+            }
+            Event::UserLeft(id) => {
+                self.window.remove_contact(id);
+            }
+            Event::UserUpdated(user) => {
+                self.window.update_contact(user);
+            }
+            Event::CallReceived(name) => {
+                self.active_call = Some(ActiveCall::new(name.clone(), CallDirection::Incoming));
+
+                // display a dialog where user can accept/reject the message
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some(&format!("Incoming call from {name}")),
+                    Some(&format!(
+                        "Receiving a call from {name}. Do you want to accept or reject this call?"
+                    )),
+                );
+                dialog.add_responses(&[("accept", "Accept"), ("reject", "Reject")]);
+                dialog.set_response_appearance("accept", ResponseAppearance::Suggested);
+                dialog.set_response_appearance("reject", ResponseAppearance::Destructive);
+                let window = self.window.clone();
+
+                dialog.run_async(None, move |_obj, response| match response {
+                    "accept" => {
+                        window.accept_call();
+                    }
+                    "reject" => {
+                        window.reject_call();
+                    }
+                    // here dialog got closed from outside, that means sender hung up
+                    _ => {
+                        let dialog = adw::MessageDialog::new(
+                            Some(&window),
+                            Some(&format!("Caller hung up.")),
+                            Some(&format!(
+                                "The call from the user {name} terminated. Caller hung up."
+                            )),
+                        );
+
+                        dialog.add_responses(&[("ok", "OK")]);
+                        dialog.run_async(None, move |obj, response| {});
+                    }
+                });
+                self.current_dialog = Some(dialog);
+            }
+            Event::CallHangup(name) => {
+                info!("Received hangup");
+                self.finish_call(CallOutcome::Missed);
+                if let Some(dialog) = self.current_dialog.take() {
+                    dialog.close();
+                }
+            }
+            Event::CallEnded => {
+                self.finish_call(CallOutcome::Missed);
+                self.window.hide_call();
+            }
+            Event::CallAccepted => {
+                if let Some(call) = self.active_call.as_mut() {
+                    call.answer();
+                    self.window.show_call(call.peer());
+                }
+                if let Some(dialog) = self.current_dialog.take() {
+                    info!("CLOSING");
+                    dialog.close();
+                }
+            }
+            Event::CallRejected(name) => {
+                self.finish_call(CallOutcome::Rejected);
+                if let Some(dialog) = self.current_dialog.take() {
+                    info!("CLOSING");
+                    dialog.close();
+                }
+
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some(&format!("Call rejected")),
+                    Some(&format!("Recepient {name} rejected the call.")),
+                );
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |obj, response| {});
+            }
+            Event::RecordingStarted(path) => {
+                info!("recording call to {}", path.display());
+                self.window.set_recording(true);
+            }
+            Event::RecordingStopped => {
+                self.window.set_recording(false);
+            }
+            Event::RecordingFailed(reason) => {
+                self.window.set_recording(false);
+
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some("Recording failed"),
+                    Some(&format!("The call couldn't be recorded: {reason}")),
+                );
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+            Event::PeerRecording(active) => {
+                self.window.set_peer_recording(active);
+            }
+            Event::CallStats(stats) => {
+                self.window.set_call_stats(&stats);
+            }
+            Event::Announcement(text) => {
+                self.window.show_announcement(&text);
+            }
+            Event::CallNoAnswer(name) => {
+                self.finish_call(CallOutcome::Missed);
+                if let Some(dialog) = self.current_dialog.take() {
+                    dialog.close();
+                }
+
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some("No answer"),
+                    Some(&format!("Recepient {name} didn't answer the call.")),
+                );
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+            Event::Disconnected(reason) => {
+                self.finish_call(CallOutcome::Missed);
+                self.client = None;
+                if let Some(dialog) = self.current_dialog.take() {
+                    dialog.close();
+                }
+                self.window.hide_call();
+                self.window.show_login();
+
+                let body = match reason {
+                    Some(reason) => format!("The connection to the server was lost: {reason}"),
+                    None => "The server closed the connection.".to_string(),
+                };
+                let dialog =
+                    adw::MessageDialog::new(Some(&self.window), Some("Disconnected"), Some(&body));
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+            Event::CallDoNotDisturb(name) => {
+                self.finish_call(CallOutcome::Rejected);
+                if let Some(dialog) = self.current_dialog.take() {
+                    dialog.close();
+                }
+
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some("Do not disturb"),
+                    Some(&format!(
+                        "Recepient {name} doesn't want to be disturbed right now. Try again later."
+                    )),
+                );
+
+                dialog.add_responses(&[("ok", "OK")]);
+                dialog.run_async(None, move |_obj, _response| {});
+            }
+        }
+    }
+
+    async fn handle_gui_event(&mut self, event: GuiEvent) {
+        match event {
+            GuiEvent::CallStart(id, name) => {
+                let dialog = adw::MessageDialog::new(
+                    Some(&self.window),
+                    Some(&format!("Calling {name}")),
+                    Some(&format!(
+                    "Waiting for a response from {name}. You can wait for the answer or hangup."
+                )),
+                );
+                dialog.add_responses(&[("hangup", "Hang up")]);
+                dialog.set_response_appearance("hangup", ResponseAppearance::Destructive);
+
+                let window = self.window.clone();
+                dialog.run_async(None, move |obj, response| {
+                    if response == "hangup" {
+                        info!("SENDING HANGUP");
+                        window.hangup_call();
+                    }
+                });
+                self.current_dialog = Some(dialog);
+                self.active_call = Some(ActiveCall::new(name.clone(), CallDirection::Outgoing));
+
+                self.request(|client| async move { client.call(id, &name).await })
+                    .await;
+            }
+            GuiEvent::CallAccepted => {
+                if let Some(call) = self.active_call.as_mut() {
+                    call.answer();
+                    self.window.show_call(call.peer());
+                }
+                self.request(|client| async move { client.accept().await })
+                    .await;
+            }
+
+            GuiEvent::CallRejected => {
+                self.finish_call(CallOutcome::Rejected);
+                self.request(|client| async move { client.reject().await })
+                    .await;
+            }
+            GuiEvent::CallHangup => {
+                self.finish_call(CallOutcome::Missed);
+                self.window.hide_call();
+                self.request(|client| async move { client.hangup().await })
+                    .await;
+            }
+            GuiEvent::NameEntered(name) => {
+                let saved = name.clone();
+                self.save_settings(|settings| settings.name = Some(saved));
+                self.connect(&name).await;
+            }
+            GuiEvent::PresenceChanged(presence, status) => {
+                self.request(|client| async move { client.set_presence(presence, &status).await })
+                    .await;
+            }
+            GuiEvent::RecordingToggled(true) => {
+                let peer = match &self.active_call {
+                    Some(call) => call.peer().to_string(),
+                    None => return,
+                };
+                let path = recording_path(&peer);
+                self.request(|client| async move { client.start_recording(path).await })
+                    .await;
+            }
+            GuiEvent::RecordingToggled(false) => {
+                self.request(|client| async move { client.stop_recording().await })
+                    .await;
+            }
+            GuiEvent::AudioProcessingChanged(processing) => {
+                self.save_settings(|settings| settings.call.audio_processing = processing);
+                self.settings.call.audio_processing = processing;
+                self.update_call_config().await;
+            }
+            GuiEvent::PreferencesChanged(preferences) => {
+                let saved = preferences.clone();
+                self.save_settings(move |settings| {
+                    settings.server = saved.server;
+                    settings.call.devices = saved.call.devices;
+                    settings.call.opus = saved.call.opus;
+                });
+                // a new server is used when connecting the next time
+                self.settings.server = preferences.server;
+                self.settings.call.devices = preferences.call.devices;
+                self.settings.call.opus = preferences.call.opus;
+                self.update_call_config().await;
+            }
+        }
+    }
+}
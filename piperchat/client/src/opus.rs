@@ -0,0 +1,389 @@
+//! Opus encoder settings, and negotiating them with the peer in the SDP `fmtp` of the audio
+//! stream: ours are advertised so that the peer's decoder makes use of them, and the peer's
+//! limit how we encode for it.
+
+use std::ops::Range;
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// Packet durations the encoder supports, in milliseconds
+pub const FRAME_SIZES: [u32; 4] = [10, 20, 40, 60];
+
+/// What the encoder should optimize for
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OpusAudioType {
+    #[default]
+    Voice,
+    Music,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct OpusConfig {
+    /// In-band forward error correction, recovering a lost packet from the one after it
+    pub fec: bool,
+    /// Discontinuous transmission, sending almost nothing during silence
+    pub dtx: bool,
+    /// Packet loss the encoder expects, in percent. FEC is only added when it's above 0.
+    pub expected_loss: u32,
+    /// Bits per second
+    pub bitrate: u32,
+    /// Duration of a packet in milliseconds: 10, 20, 40 or 60
+    #[serde(deserialize_with = "deserialize_frame_size")]
+    pub frame_size: u32,
+    pub audio_type: OpusAudioType,
+    /// Conceal lost packets FEC couldn't recover instead of playing silence
+    pub plc: bool,
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        OpusConfig {
+            fec: true,
+            dtx: false,
+            expected_loss: 10,
+            bitrate: 32_000,
+            frame_size: 20,
+            audio_type: OpusAudioType::Voice,
+            plc: true,
+        }
+    }
+}
+
+impl OpusConfig {
+    /// Pipeline fragment of the configured encoder
+    pub fn encoder_description(&self) -> String {
+        let audio_type = match self.audio_type {
+            OpusAudioType::Voice => "voice",
+            OpusAudioType::Music => "generic",
+        };
+
+        format!(
+            "opusenc inband-fec={} dtx={} packet-loss-percentage={} bitrate={} frame-size={} \
+            audio-type={audio_type}",
+            self.fec,
+            self.dtx,
+            self.expected_loss.min(100),
+            self.bitrate,
+            self.frame_size,
+        )
+    }
+
+    // Parameters of the `fmtp` line, telling the peer what we'd like to receive
+    fn fmtp_parameters(&self) -> [(&'static str, String); 4] {
+        [
+            ("minptime", "10".to_string()),
+            ("useinbandfec", u8::from(self.fec).to_string()),
+            ("usedtx", u8::from(self.dtx).to_string()),
+            ("maxaveragebitrate", self.bitrate.to_string()),
+        ]
+    }
+
+    /// The settings to encode with for a peer asking for `peer`: FEC and DTX only when both
+    /// sides want them, the bitrate at most what the peer takes, and the supported frame size
+    /// closest to the packet duration it prefers
+    pub fn for_peer(&self, peer: &PeerOpusParameters) -> OpusConfig {
+        let frame_size = peer
+            .ptime
+            .and_then(|ptime| {
+                FRAME_SIZES
+                    .iter()
+                    .copied()
+                    .min_by_key(|frame_size| frame_size.abs_diff(ptime))
+            })
+            .unwrap_or(self.frame_size);
+
+        OpusConfig {
+            fec: self.fec && peer.fec,
+            dtx: self.dtx && peer.dtx,
+            bitrate: peer
+                .max_bitrate
+                .map_or(self.bitrate, |max_bitrate| self.bitrate.min(max_bitrate)),
+            frame_size,
+            ..self.clone()
+        }
+    }
+
+    /// Add the settings to the `fmtp` and `ptime` attributes of every Opus payload in `sdp`,
+    /// keeping any other parameters already there
+    pub fn munge_sdp(&self, sdp: &str) -> String {
+        let mut lines: Vec<String> = sdp.lines().map(str::to_string).collect();
+
+        for payload in opus_payloads(&lines) {
+            let fmtp = format!("a=fmtp:{payload} ");
+            match lines.iter().position(|line| line.starts_with(&fmtp)) {
+                Some(index) => {
+                    let mut parameters = parse_parameters(&lines[index][fmtp.len()..]);
+                    for (key, value) in self.fmtp_parameters() {
+                        match parameters.iter_mut().find(|(existing, _)| existing == key) {
+                            Some((_, existing)) => *existing = value,
+                            None => parameters.push((key.to_string(), value)),
+                        }
+                    }
+                    lines[index] = format!("{fmtp}{}", join_parameters(parameters));
+                }
+                None => {
+                    let rtpmap = format!("a=rtpmap:{payload} ");
+                    let index = lines
+                        .iter()
+                        .position(|line| line.starts_with(&rtpmap))
+                        .expect("payload comes from an rtpmap line");
+                    let parameters = self
+                        .fmtp_parameters()
+                        .into_iter()
+                        .map(|(key, value)| (key.to_string(), value));
+                    lines.insert(index + 1, format!("{fmtp}{}", join_parameters(parameters)));
+                }
+            }
+
+            // ptime belongs to the media section containing the payload
+            let section = lines
+                .iter()
+                .position(|line| line.starts_with(&fmtp))
+                .and_then(|index| media_section(&lines, index));
+            if let Some(Range { start, end }) = section {
+                let ptime = format!("a=ptime:{}", self.frame_size);
+                match lines[start..end]
+                    .iter()
+                    .position(|line| line.starts_with("a=ptime:"))
+                {
+                    Some(index) => lines[start + index] = ptime,
+                    None => lines.insert(end, ptime),
+                }
+            }
+        }
+
+        let mut sdp = lines.join("\r\n");
+        sdp.push_str("\r\n");
+        sdp
+    }
+}
+
+/// What the peer asks for in the `fmtp` and `ptime` attributes of the Opus payload in its
+/// description. What it leaves out has the defaults of RFC 7587.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerOpusParameters {
+    /// Its decoder makes use of in-band FEC
+    pub fec: bool,
+    /// It prefers discontinuous transmission
+    pub dtx: bool,
+    /// Most bits per second it takes
+    pub max_bitrate: Option<u32>,
+    /// Duration of a packet it prefers, in milliseconds
+    pub ptime: Option<u32>,
+}
+
+impl PeerOpusParameters {
+    /// The parameters of the first Opus payload in `sdp`, none if there's no such payload
+    pub fn parse(sdp: &str) -> Option<Self> {
+        let lines: Vec<&str> = sdp.lines().collect();
+        let payload = opus_payloads(&lines).into_iter().next()?;
+        let mut peer = PeerOpusParameters::default();
+
+        let fmtp = format!("a=fmtp:{payload} ");
+        let parameters = lines
+            .iter()
+            .find_map(|line| line.strip_prefix(fmtp.as_str()))
+            .map(parse_parameters)
+            .unwrap_or_default();
+        for (key, value) in parameters {
+            match key.as_str() {
+                "useinbandfec" => peer.fec = value == "1",
+                "usedtx" => peer.dtx = value == "1",
+                "maxaveragebitrate" => peer.max_bitrate = value.parse().ok(),
+                _ => (),
+            }
+        }
+
+        let rtpmap = format!("a=rtpmap:{payload} ");
+        let section = lines
+            .iter()
+            .position(|line| line.starts_with(&rtpmap))
+            .and_then(|index| media_section(&lines, index));
+        if let Some(section) = section {
+            peer.ptime = lines[section]
+                .iter()
+                .find_map(|line| line.strip_prefix("a=ptime:")?.trim().parse().ok());
+        }
+
+        Some(peer)
+    }
+}
+
+// The payload types of the Opus encodings in the lines of an SDP
+fn opus_payloads<S: AsRef<str>>(lines: &[S]) -> Vec<String> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            let (payload, encoding) = line.as_ref().strip_prefix("a=rtpmap:")?.split_once(' ')?;
+            encoding
+                .to_ascii_lowercase()
+                .starts_with("opus/")
+                .then(|| payload.to_string())
+        })
+        .collect()
+}
+
+// The lines of the media section containing the line at `index`, from its `m=` line on. None
+// for a line of the session section.
+fn media_section<S: AsRef<str>>(lines: &[S], index: usize) -> Option<Range<usize>> {
+    let is_media = |line: &S| line.as_ref().starts_with("m=");
+    let start = lines[..index].iter().rposition(is_media)?;
+    let end = lines[start + 1..]
+        .iter()
+        .position(is_media)
+        .map_or(lines.len(), |end| start + 1 + end);
+
+    Some(start..end)
+}
+
+// The `key=value` parameters of an `fmtp` attribute
+fn parse_parameters(parameters: &str) -> Vec<(String, String)> {
+    parameters
+        .split(';')
+        .filter_map(|parameter| {
+            let (key, value) = parameter.trim().split_once('=')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+// A frame size the encoder supports, anything else only fails once a call starts
+fn deserialize_frame_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let frame_size = u32::deserialize(deserializer)?;
+    if !FRAME_SIZES.contains(&frame_size) {
+        return Err(D::Error::custom(format!(
+            "unsupported frame size {frame_size}, expected one of {FRAME_SIZES:?}"
+        )));
+    }
+
+    Ok(frame_size)
+}
+
+fn join_parameters(parameters: impl IntoIterator<Item = (String, String)>) -> String {
+    parameters
+        .into_iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDP: &str = "v=0\r\n\
+        o=- 0 0 IN IP4 0.0.0.0\r\n\
+        s=-\r\n\
+        t=0 0\r\n\
+        m=video 9 UDP/TLS/RTP/SAVPF 96\r\n\
+        a=rtpmap:96 VP8/90000\r\n\
+        m=audio 9 UDP/TLS/RTP/SAVPF 97\r\n\
+        a=rtpmap:97 OPUS/48000/2\r\n\
+        a=mid:audio0\r\n";
+
+    #[test]
+    fn adds_fmtp_and_ptime() {
+        let config = OpusConfig {
+            dtx: true,
+            bitrate: 24_000,
+            frame_size: 40,
+            ..OpusConfig::default()
+        };
+        let sdp = config.munge_sdp(SDP);
+
+        assert!(sdp.contains(
+            "a=rtpmap:97 OPUS/48000/2\r\n\
+            a=fmtp:97 minptime=10;useinbandfec=1;usedtx=1;maxaveragebitrate=24000\r\n"
+        ));
+        assert!(sdp.ends_with("a=mid:audio0\r\na=ptime:40\r\n"));
+        // the video section is left alone
+        assert!(sdp.contains("a=rtpmap:96 VP8/90000\r\nm=audio"));
+    }
+
+    #[test]
+    fn merges_existing_fmtp() {
+        let sdp = SDP.replace(
+            "a=mid:audio0\r\n",
+            "a=fmtp:97 sprop-stereo=0;useinbandfec=1\r\na=ptime:20\r\na=mid:audio0\r\n",
+        );
+        let config = OpusConfig {
+            fec: false,
+            ..OpusConfig::default()
+        };
+        let sdp = config.munge_sdp(&sdp);
+
+        assert!(sdp.contains(
+            "a=fmtp:97 sprop-stereo=0;useinbandfec=0;minptime=10;usedtx=0;maxaveragebitrate=32000\r\n"
+        ));
+        assert_eq!(sdp.matches("a=fmtp:97").count(), 1);
+        assert_eq!(sdp.matches("a=ptime:").count(), 1);
+    }
+
+    #[test]
+    fn parses_the_peers_parameters() {
+        let sdp = SDP.replace(
+            "a=mid:audio0\r\n",
+            "a=fmtp:97 minptime=10; useinbandfec=1;maxaveragebitrate=16000\r\n\
+            a=ptime:60\r\na=mid:audio0\r\n",
+        );
+        let peer = PeerOpusParameters::parse(&sdp).unwrap();
+        assert_eq!(
+            peer,
+            PeerOpusParameters {
+                fec: true,
+                dtx: false,
+                max_bitrate: Some(16_000),
+                ptime: Some(60),
+            }
+        );
+
+        // what isn't there has the defaults
+        assert_eq!(
+            PeerOpusParameters::parse(SDP),
+            Some(PeerOpusParameters::default())
+        );
+        let video_only = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96\r\na=rtpmap:96 VP8/90000\r\n";
+        assert_eq!(PeerOpusParameters::parse(video_only), None);
+    }
+
+    #[test]
+    fn encodes_as_the_peer_asks() {
+        let config = OpusConfig {
+            dtx: true,
+            ..OpusConfig::default()
+        };
+        let peer = PeerOpusParameters {
+            fec: false,
+            dtx: true,
+            max_bitrate: Some(16_000),
+            ptime: Some(50),
+        };
+        let negotiated = config.for_peer(&peer);
+        assert!(!negotiated.fec);
+        assert!(negotiated.dtx);
+        assert_eq!(negotiated.bitrate, 16_000);
+        assert_eq!(negotiated.frame_size, 40);
+
+        // the peer can't raise the bitrate or turn on what we don't want
+        let peer = PeerOpusParameters {
+            fec: true,
+            max_bitrate: Some(64_000),
+            ..PeerOpusParameters::default()
+        };
+        let negotiated = OpusConfig::default().for_peer(&peer);
+        assert!(negotiated.fec);
+        assert!(!negotiated.dtx);
+        assert_eq!(negotiated.bitrate, 32_000);
+        assert_eq!(negotiated.frame_size, 20);
+    }
+
+    #[test]
+    fn rejects_unsupported_frame_sizes() {
+        let config: OpusConfig = toml::from_str("frame_size = 60").unwrap();
+        assert_eq!(config.frame_size, 60);
+        assert!(toml::from_str::<OpusConfig>("frame_size = 30").is_err());
+    }
+}
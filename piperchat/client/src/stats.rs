@@ -0,0 +1,110 @@
+//! Call quality statistics, parsed from the report of webrtcbin's `get-stats` signal.
+
+use std::time::Duration;
+
+use gst_webrtc::WebRTCStatsType;
+
+use crate::message::CallStatsMessage;
+
+/// Totals since the start of the call, plus rates since the previous sample
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CallStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Packets we sent that the peer reports as lost
+    pub packets_lost: i64,
+    /// Interarrival jitter of the received streams, in seconds
+    pub jitter: f64,
+    /// Round trip time reported by RTCP, in seconds
+    pub round_trip_time: Option<f64>,
+    /// Bits per second sent since the previous sample
+    pub send_bitrate: u64,
+    /// Bits per second received since the previous sample
+    pub receive_bitrate: u64,
+}
+
+impl CallStats {
+    /// Sum up the per-stream statistics of a `get-stats` report
+    pub fn from_report(report: &gst::StructureRef) -> Self {
+        let mut stats = CallStats::default();
+
+        for (_, value) in report.iter() {
+            let stream = match value.get::<gst::Structure>() {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let kind = match stream.get::<WebRTCStatsType>("type") {
+                Ok(kind) => kind,
+                Err(_) => continue,
+            };
+
+            match kind {
+                WebRTCStatsType::OutboundRtp => {
+                    stats.bytes_sent += stream.get::<u64>("bytes-sent").unwrap_or(0);
+                    stats.packets_sent += stream.get::<u64>("packets-sent").unwrap_or(0);
+                }
+                WebRTCStatsType::InboundRtp => {
+                    stats.bytes_received += stream.get::<u64>("bytes-received").unwrap_or(0);
+                    stats.packets_received += stream.get::<u64>("packets-received").unwrap_or(0);
+                    stats.jitter = stats.jitter.max(stream.get::<f64>("jitter").unwrap_or(0.0));
+                }
+                WebRTCStatsType::RemoteInboundRtp => {
+                    stats.packets_lost += stream.get::<i64>("packets-lost").unwrap_or(0);
+                    if let Ok(rtt) = stream.get::<f64>("round-trip-time") {
+                        let max = stats.round_trip_time.map_or(rtt, |max| max.max(rtt));
+                        stats.round_trip_time = Some(max);
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        stats
+    }
+
+    /// Fill in the rates from the difference to a sample taken `elapsed` earlier
+    pub fn update_rates(&mut self, previous: &CallStats, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if seconds <= 0.0 {
+            return;
+        }
+
+        let rate =
+            |now: u64, before: u64| (now.saturating_sub(before) as f64 * 8.0 / seconds) as u64;
+        self.send_bitrate = rate(self.bytes_sent, previous.bytes_sent);
+        self.receive_bitrate = rate(self.bytes_received, previous.bytes_received);
+    }
+
+    /// Fraction of the sent packets that got lost, between 0 and 1
+    pub fn loss(&self) -> f64 {
+        let total = self.packets_sent as f64;
+        if total == 0.0 {
+            0.0
+        } else {
+            (self.packets_lost.max(0) as f64 / total).min(1.0)
+        }
+    }
+
+    /// Summary reported to the server once the call ends
+    pub fn summary(&self, duration: Duration) -> CallStatsMessage {
+        CallStatsMessage {
+            duration: duration.as_secs(),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            packets_lost: self.packets_lost,
+            jitter: self.jitter,
+            round_trip_time: self.round_trip_time,
+        }
+    }
+}
+
+/// Human-readable bitrate, e.g. "1.2 Mbit/s"
+pub fn format_bitrate(bitrate: u64) -> String {
+    match bitrate {
+        0..=999 => format!("{bitrate} bit/s"),
+        1_000..=999_999 => format!("{:.0} kbit/s", bitrate as f64 / 1e3),
+        _ => format!("{:.1} Mbit/s", bitrate as f64 / 1e6),
+    }
+}
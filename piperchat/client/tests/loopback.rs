@@ -0,0 +1,86 @@
+//! Two calls in one process, negotiating over in-memory channels instead of the server.
+//! They need GStreamer and the plugins below, so they only run when asked for with
+//! `cargo test -- --ignored`, and fail when something is missing.
+
+use std::time::Duration;
+
+use futures::{select, StreamExt};
+
+use piperchat::session::{App, CallConfig, CallSide, MediaKind, MediaSink, MediaSource};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+const REQUIRED_ELEMENTS: &[&str] = &[
+    "videotestsrc",
+    "audiotestsrc",
+    "vp8enc",
+    "vp8dec",
+    "opusenc",
+    "opusdec",
+    "webrtcbin",
+    "nicesrc",
+    "fakesink",
+];
+
+fn missing_elements() -> Vec<&'static str> {
+    REQUIRED_ELEMENTS
+        .iter()
+        .copied()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect()
+}
+
+fn media_arrived(app: &App) -> bool {
+    app.received_buffers(MediaKind::Video) > 0 && app.received_buffers(MediaKind::Audio) > 0
+}
+
+#[test]
+#[ignore = "needs GStreamer and its plugins, run with --ignored"]
+fn media_flows_both_ways() -> anyhow::Result<()> {
+    gst::init()?;
+    let missing = missing_elements();
+    if !missing.is_empty() {
+        anyhow::bail!("missing GStreamer elements: {missing:?}");
+    }
+
+    let config = CallConfig {
+        source: MediaSource::Test,
+        sink: MediaSink::Fake,
+        ..CallConfig::default()
+    };
+    let (caller, caller_bus, caller_rx) = App::new(CallSide::Caller, config.clone())?;
+    let (callee, callee_bus, callee_rx) = App::new(CallSide::Callee, config)?;
+
+    async_std::task::block_on(async {
+        let mut caller_bus = caller_bus.fuse();
+        let mut callee_bus = callee_bus.fuse();
+        let mut caller_rx = caller_rx.fuse();
+        let mut callee_rx = callee_rx.fuse();
+        let mut ticks = async_std::stream::interval(Duration::from_millis(100)).fuse();
+        let mut deadline = Box::pin(async_std::task::sleep(TIMEOUT).fuse());
+
+        loop {
+            select! {
+                message = caller_rx.select_next_some() => callee.handle_webrtc_message(message)?,
+                message = callee_rx.select_next_some() => caller.handle_webrtc_message(message)?,
+                message = caller_bus.select_next_some() => caller.handle_pipeline_message(&message)?,
+                message = callee_bus.select_next_some() => callee.handle_pipeline_message(&message)?,
+                _ = ticks.select_next_some() => {
+                    if media_arrived(&caller) && media_arrived(&callee) {
+                        return Ok(());
+                    }
+                }
+                _ = &mut deadline => {
+                    anyhow::bail!(
+                        "no media within {TIMEOUT:?}: caller received {} video and {} audio buffers, \
+                        callee received {} video and {} audio buffers",
+                        caller.received_buffers(MediaKind::Video),
+                        caller.received_buffers(MediaKind::Audio),
+                        callee.received_buffers(MediaKind::Video),
+                        callee.received_buffers(MediaKind::Audio),
+                    );
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,158 @@
+//! The call state machine from `state-diagram.txt`, shared by the client and the server.
+//!
+//! The state is generic over the peer `P` it's talking to: the client keeps the peer's
+//! name, while the server keeps the channel of the peer's connection.
+
+use std::fmt;
+
+/// The state of a single connection with respect to calls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallState<P> {
+    /// Connected, not in a call
+    Idle,
+    /// We're calling the peer and wait for an answer
+    Requested(P),
+    /// The peer is calling us
+    Received(P),
+    /// The call was accepted
+    InCall(P),
+}
+
+/// Everything that can happen to a call, from the point of view of one side
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallEvent<P> {
+    /// We call the peer
+    Dial(P),
+    /// The peer calls us
+    Incoming(P),
+    /// We accept an incoming call
+    Accept,
+    /// We reject an incoming call
+    Reject,
+    /// The peer accepted our call
+    Accepted,
+    /// The peer didn't take our call, for whatever reason
+    Rejected,
+    /// We end the call, or cancel it while it's ringing
+    Hangup,
+    /// The peer ended the call, or cancelled it while it was ringing
+    PeerHangup,
+    /// The connection is gone, which ends any call
+    Disconnect,
+}
+
+/// The state without the peer, for reporting
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Idle,
+    Requested,
+    Received,
+    InCall,
+}
+
+/// An event that isn't legal in the current state. The state is left unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransitionError {
+    pub phase: Phase,
+    pub event: &'static str,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            Phase::Idle => "idle",
+            Phase::Requested => "calling",
+            Phase::Received => "ringing",
+            Phase::InCall => "in a call",
+        };
+        f.write_str(phase)
+    }
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is not allowed while {}", self.event, self.phase)
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl<P> CallEvent<P> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallEvent::Dial(_) => "dial",
+            CallEvent::Incoming(_) => "incoming call",
+            CallEvent::Accept => "accept",
+            CallEvent::Reject => "reject",
+            CallEvent::Accepted => "call accepted",
+            CallEvent::Rejected => "call rejected",
+            CallEvent::Hangup => "hangup",
+            CallEvent::PeerHangup => "peer hangup",
+            CallEvent::Disconnect => "disconnect",
+        }
+    }
+}
+
+impl<P> CallState<P> {
+    pub fn phase(&self) -> Phase {
+        match self {
+            CallState::Idle => Phase::Idle,
+            CallState::Requested(_) => Phase::Requested,
+            CallState::Received(_) => Phase::Received,
+            CallState::InCall(_) => Phase::InCall,
+        }
+    }
+
+    pub fn peer(&self) -> Option<&P> {
+        match self {
+            CallState::Idle => None,
+            CallState::Requested(peer) | CallState::Received(peer) | CallState::InCall(peer) => {
+                Some(peer)
+            }
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self, CallState::Idle)
+    }
+
+    /// Apply `event` to the state. When the event ends the call, the peer of that call is
+    /// returned so that it can be notified.
+    pub fn handle(&mut self, event: CallEvent<P>) -> Result<Option<P>, TransitionError> {
+        let error = TransitionError {
+            phase: self.phase(),
+            event: event.name(),
+        };
+
+        let (next, ended) = match (std::mem::replace(self, CallState::Idle), event) {
+            (CallState::Idle, CallEvent::Dial(peer)) => (CallState::Requested(peer), None),
+            (CallState::Idle, CallEvent::Incoming(peer)) => (CallState::Received(peer), None),
+            (CallState::Idle, CallEvent::Disconnect) => (CallState::Idle, None),
+
+            (CallState::Requested(peer), CallEvent::Accepted) => (CallState::InCall(peer), None),
+            (
+                CallState::Requested(peer),
+                CallEvent::Rejected | CallEvent::Hangup | CallEvent::Disconnect,
+            ) => (CallState::Idle, Some(peer)),
+
+            (CallState::Received(peer), CallEvent::Accept) => (CallState::InCall(peer), None),
+            (
+                CallState::Received(peer),
+                CallEvent::Reject | CallEvent::PeerHangup | CallEvent::Disconnect,
+            ) => (CallState::Idle, Some(peer)),
+
+            (
+                CallState::InCall(peer),
+                CallEvent::Hangup | CallEvent::PeerHangup | CallEvent::Disconnect,
+            ) => (CallState::Idle, Some(peer)),
+
+            (state, _) => {
+                *self = state;
+                return Err(error);
+            }
+        };
+
+        *self = next;
+        Ok(ended)
+    }
+}
@@ -0,0 +1,126 @@
+use piperchat_protocol::call::{CallEvent, CallState, Phase};
+use proptest::prelude::*;
+
+// The transitions from `state-diagram.txt`, written out independently of the implementation
+fn expected(phase: Phase, event: &CallEvent<u32>) -> Option<Phase> {
+    use CallEvent::*;
+
+    match (phase, event) {
+        (Phase::Idle, Dial(_)) => Some(Phase::Requested),
+        (Phase::Idle, Incoming(_)) => Some(Phase::Received),
+        (Phase::Idle, Disconnect) => Some(Phase::Idle),
+        (Phase::Requested, Accepted) => Some(Phase::InCall),
+        (Phase::Requested, Rejected | Hangup | Disconnect) => Some(Phase::Idle),
+        (Phase::Received, Accept) => Some(Phase::InCall),
+        (Phase::Received, Reject | PeerHangup | Disconnect) => Some(Phase::Idle),
+        (Phase::InCall, Hangup | PeerHangup | Disconnect) => Some(Phase::Idle),
+        _ => None,
+    }
+}
+
+fn all_events(peer: u32) -> Vec<CallEvent<u32>> {
+    vec![
+        CallEvent::Dial(peer),
+        CallEvent::Incoming(peer),
+        CallEvent::Accept,
+        CallEvent::Reject,
+        CallEvent::Accepted,
+        CallEvent::Rejected,
+        CallEvent::Hangup,
+        CallEvent::PeerHangup,
+        CallEvent::Disconnect,
+    ]
+}
+
+fn all_states(peer: u32) -> Vec<CallState<u32>> {
+    vec![
+        CallState::Idle,
+        CallState::Requested(peer),
+        CallState::Received(peer),
+        CallState::InCall(peer),
+    ]
+}
+
+fn event() -> impl Strategy<Value = CallEvent<u32>> {
+    prop_oneof![
+        any::<u32>().prop_map(CallEvent::Dial),
+        any::<u32>().prop_map(CallEvent::Incoming),
+        Just(CallEvent::Accept),
+        Just(CallEvent::Reject),
+        Just(CallEvent::Accepted),
+        Just(CallEvent::Rejected),
+        Just(CallEvent::Hangup),
+        Just(CallEvent::PeerHangup),
+        Just(CallEvent::Disconnect),
+    ]
+}
+
+#[test]
+fn every_transition_matches_the_diagram() {
+    for state in all_states(7) {
+        for event in all_events(7) {
+            let mut next = state.clone();
+            let expected = expected(state.phase(), &event);
+            let result = next.handle(event);
+
+            match expected {
+                Some(phase) => {
+                    assert!(result.is_ok(), "{:?} should be legal in {:?}", result, state);
+                    assert_eq!(next.phase(), phase);
+                }
+                None => {
+                    let err = result.unwrap_err();
+                    assert_eq!(err.phase, state.phase());
+                    assert_eq!(next, state);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn illegal_transition_is_reported() {
+    let mut state = CallState::InCall(1);
+    let err = state.handle(CallEvent::Dial(2)).unwrap_err();
+
+    assert_eq!(state, CallState::InCall(1));
+    assert_eq!(err.to_string(), "dial is not allowed while in a call");
+}
+
+proptest! {
+    #[test]
+    fn random_sequences_follow_the_diagram(events in prop::collection::vec(event(), 0..64)) {
+        let mut state = CallState::Idle;
+        // the peer of the call we're in, tracked alongside the state machine
+        let mut peer: Option<u32> = None;
+
+        for event in events {
+            let before = state.clone();
+            let expected = expected(state.phase(), &event);
+            let dialed = match &event {
+                CallEvent::Dial(peer) | CallEvent::Incoming(peer) => Some(*peer),
+                _ => None,
+            };
+
+            match state.handle(event) {
+                Ok(ended) => {
+                    prop_assert_eq!(Some(state.phase()), expected);
+                    if state.is_idle() {
+                        prop_assert_eq!(ended, peer.take());
+                    } else {
+                        prop_assert_eq!(ended, None);
+                        peer = peer.or(dialed);
+                    }
+                }
+                Err(err) => {
+                    prop_assert_eq!(expected, None);
+                    prop_assert_eq!(err.phase, before.phase());
+                    prop_assert_eq!(&state, &before);
+                }
+            }
+
+            // only an idle connection has no peer
+            prop_assert_eq!(state.peer().copied(), peer);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Load on the user registry with a growing number of connected users: how long it takes to
+//! join, and to get a presence change out to everyone. Run with `cargo bench`.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::future::join_all;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use piperchat_protocol::{ConnectMessage, Message, Presence, PresenceMessage};
+use piperchat_server::config::Limits;
+use piperchat_server::Config;
+
+const USERS: [usize; 3] = [10, 100, 1000];
+
+type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+// A server that lets all the clients in and doesn't limit them
+async fn start_server() -> SocketAddr {
+    let config = Config {
+        limits: Limits {
+            max_users: 100_000,
+            max_connections_per_ip: 100_000,
+            messages_per_second: 1e9,
+            message_burst: u32::MAX,
+            queue_size: 4096,
+            ..Limits::default()
+        },
+        ..Config::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(piperchat_server::serve(listener, config));
+
+    address
+}
+
+async fn send(client: &mut Client, message: &Message) {
+    let text = serde_json::to_string(message).unwrap();
+    client.send(WsMessage::Text(text)).await.unwrap();
+}
+
+// Skip messages until one matches
+async fn wait_for(client: &mut Client, matches: impl Fn(&Message) -> bool) {
+    while let Some(message) = client.next().await {
+        if let WsMessage::Text(text) = message.unwrap() {
+            if matches(&serde_json::from_str(&text).unwrap()) {
+                return;
+            }
+        }
+    }
+    panic!("connection closed");
+}
+
+// Register a new user and wait for the list of users
+async fn connect(address: SocketAddr) -> Client {
+    let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{address}"))
+        .await
+        .unwrap();
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let name = format!("user{id}");
+    send(&mut client, &Message::Connect(ConnectMessage { name, id })).await;
+    wait_for(&mut client, |message| {
+        matches!(message, Message::UserList(_))
+    })
+    .await;
+
+    client
+}
+
+fn join(c: &mut Criterion) {
+    let mut group = c.benchmark_group("join");
+    for users in USERS {
+        // a runtime per size, so the connections go away with it
+        let runtime = Runtime::new().unwrap();
+        let address = runtime.block_on(async {
+            let address = start_server().await;
+            for _ in 0..users {
+                // nobody's interested in the others joining and leaving
+                let mut client = connect(address).await;
+                tokio::spawn(async move { while client.next().await.is_some() {} });
+            }
+            address
+        });
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(users),
+            &address,
+            |b, &address| {
+                b.iter(|| {
+                    runtime.block_on(async {
+                        connect(address).await.close(None).await.unwrap();
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn presence(c: &mut Criterion) {
+    let mut group = c.benchmark_group("presence");
+    for users in USERS {
+        let runtime = Runtime::new().unwrap();
+        let (mut sender, mut others) = runtime.block_on(async {
+            let address = start_server().await;
+            let mut clients = Vec::new();
+            for _ in 0..users {
+                clients.push(connect(address).await);
+            }
+            let sender = clients.pop().unwrap();
+            (sender, clients)
+        });
+
+        // from the change being sent until every other user has it
+        let mut busy = false;
+        group.bench_function(BenchmarkId::from_parameter(users), |b| {
+            b.iter(|| {
+                busy = !busy;
+                let presence = if busy {
+                    Presence::Busy
+                } else {
+                    Presence::Available
+                };
+                runtime.block_on(async {
+                    let message = Message::SetPresence(PresenceMessage {
+                        presence,
+                        status: String::new(),
+                    });
+                    send(&mut sender, &message).await;
+                    join_all(others.iter_mut().map(|client| {
+                        wait_for(client, move |message| {
+                            matches!(message, Message::UserUpdated(user) if user.presence == presence)
+                        })
+                    }))
+                    .await;
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, join, presence);
+criterion_main!(benches);
@@ -0,0 +1,111 @@
+//! HTTP endpoint for operators:
+//!
+//! - `GET /metrics`: Prometheus metrics
+//! - `GET /users`: the connected users and their calls, as JSON
+//!
+//! It has no authentication of its own, so it should only listen on a private address.
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, StatusCode};
+use serde::Serialize;
+use tokio::net::TcpListener;
+
+use piperchat_protocol::call::Phase;
+use piperchat_protocol::Presence;
+
+use crate::metrics::Metrics;
+use crate::registry::{Registry, User};
+
+#[derive(Serialize)]
+struct UserView<'a> {
+    id: u32,
+    name: &'a str,
+    presence: Presence,
+    status: &'a str,
+    address: IpAddr,
+    call: &'static str,
+    /// The user on the other end of the call
+    peer: Option<u32>,
+}
+
+impl<'a> From<&'a User> for UserView<'a> {
+    fn from(user: &'a User) -> Self {
+        let call = match user.phase {
+            Phase::Idle => "idle",
+            Phase::Requested => "requested",
+            Phase::Received => "received",
+            Phase::InCall => "incall",
+        };
+
+        UserView {
+            id: user.id,
+            name: &user.name,
+            presence: user.presence,
+            status: &user.status,
+            address: user.address,
+            call,
+            peer: user.peer.as_ref().map(|(_, id)| *id),
+        }
+    }
+}
+
+/// Answer requests on `listener` until an error occurs
+pub(crate) async fn serve(
+    listener: TcpListener,
+    registry: Registry,
+    metrics: Arc<Metrics>,
+) -> color_eyre::Result<()> {
+    let make_service = make_service_fn(move |_connection| {
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let registry = registry.clone();
+                let metrics = metrics.clone();
+                async move { Ok::<_, Infallible>(respond(&request, &registry, &metrics).await) }
+            }))
+        }
+    });
+
+    hyper::Server::builder(AddrIncoming::from_listener(listener)?)
+        .serve(make_service)
+        .await?;
+
+    Ok(())
+}
+
+async fn respond(
+    request: &Request<Body>,
+    registry: &Registry,
+    metrics: &Metrics,
+) -> Response<Body> {
+    let (content_type, body) = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            let metrics = metrics.encode(&registry.users().await);
+            ("text/plain; version=0.0.4", metrics)
+        }
+        (&Method::GET, "/users") => {
+            let users = registry.users().await;
+            let mut users: Vec<UserView> = users.iter().map(UserView::from).collect();
+            users.sort_by_key(|user| user.id);
+            let users = serde_json::to_string_pretty(&users).expect("can't serialize users");
+            ("application/json", users)
+        }
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .unwrap()
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
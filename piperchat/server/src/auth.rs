@@ -0,0 +1,78 @@
+//! Checks who may log in. With the `tokens` backend clients pass their token in the query of
+//! the server URL, e.g. `wss://chat.example.com/?token=secret`, percent-encoded like any
+//! other parameter, and log in under the name the token belongs to.
+//!
+//! The tokens file has a `name:sha256` line per user, where `sha256` is the hex digest of the
+//! token, as printed by `printf %s secret | sha256sum`. Empty lines and lines starting with
+//! `#` are skipped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{bail, eyre, WrapErr};
+use sha2::{Digest, Sha256};
+
+use crate::config::AuthConfig;
+
+#[derive(Debug)]
+pub(crate) enum Auth {
+    Open,
+    /// Token digests by user name
+    Tokens(HashMap<String, String>),
+}
+
+impl Auth {
+    pub(crate) fn new(config: &AuthConfig) -> color_eyre::Result<Self> {
+        match config {
+            AuthConfig::Open => Ok(Auth::Open),
+            AuthConfig::Tokens { file } => read_tokens(file)
+                .map(Auth::Tokens)
+                .wrap_err_with(|| format!("invalid tokens file {}", file.display())),
+        }
+    }
+
+    /// Check that `token` lets the user log in as `name`
+    pub(crate) fn check(&self, name: &str, token: Option<&str>) -> Result<(), String> {
+        let digests = match self {
+            Auth::Open => return Ok(()),
+            Auth::Tokens(digests) => digests,
+        };
+
+        let token = token.ok_or("This server requires a token")?;
+        match digests.get(name) {
+            Some(digest) if *digest == format!("{:x}", Sha256::digest(token)) => Ok(()),
+            _ => Err("Wrong name or token".to_string()),
+        }
+    }
+}
+
+fn read_tokens(path: &Path) -> color_eyre::Result<HashMap<String, String>> {
+    let content = fs::read_to_string(path)?;
+    let mut digests = HashMap::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (name, digest) = line
+            .split_once(':')
+            .ok_or_else(|| eyre!("line {}: expected name:sha256", number + 1))?;
+        let digest = digest.trim().to_ascii_lowercase();
+        if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            bail!("line {}: not a sha256 hex digest", number + 1);
+        }
+        digests.insert(name.trim().to_string(), digest);
+    }
+
+    Ok(digests)
+}
+
+/// The `token` parameter in the query of the URL the client connected to, decoded
+pub(crate) fn token_from_query(query: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "token")
+        .map(|(_, token)| token.into_owned())
+}
@@ -0,0 +1,56 @@
+//! Messaging between the instances of the server, so users connected to different ones can see
+//! and call each other. Delivery is best effort: what's lost while an instance can't reach the
+//! bus, or falls behind, is gone.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use tokio::sync::mpsc;
+
+/// Messages waiting for a subscriber, more are dropped until it catches up
+pub const QUEUE_SIZE: usize = 1024;
+
+/// Publish/subscribe messaging between the instances
+pub trait Bus: fmt::Debug + Send + Sync {
+    /// Send `payload` to everyone subscribed to `channel`, the publisher included. False when
+    /// the bus is falling behind and dropped it, for some subscribers or all of them.
+    fn publish(&self, channel: &str, payload: Vec<u8>) -> bool;
+
+    /// Receive what's published on `channel`. Whatever is published after this returns is
+    /// received, including what the subscriber publishes itself, as long as the subscriber
+    /// keeps up.
+    fn subscribe(&self, channel: &str) -> mpsc::Receiver<Vec<u8>>;
+}
+
+/// A bus within the process. Servers sharing one make up a cluster, a server with one of its
+/// own is on its own.
+#[derive(Debug, Default)]
+pub struct MemoryBus {
+    subscribers: Mutex<HashMap<String, Vec<mpsc::Sender<Vec<u8>>>>>,
+}
+
+impl Bus for MemoryBus {
+    fn publish(&self, channel: &str, payload: Vec<u8>) -> bool {
+        let mut delivered = true;
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(subscribers) = subscribers.get_mut(channel) {
+            subscribers.retain(|subscriber| match subscriber.try_send(payload.clone()) {
+                Ok(()) => true,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    delivered = false;
+                    true
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => false,
+            });
+        }
+        delivered
+    }
+
+    fn subscribe(&self, channel: &str) -> mpsc::Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.entry(channel.to_string()).or_default().push(tx);
+        rx
+    }
+}
@@ -0,0 +1,225 @@
+//! What the instances of a cluster tell each other over the bus. They share a channel for the
+//! directory of users, and each has one of its own for the commands to its users.
+//!
+//! A starting node says hello and the others reply with their users, from then on they keep
+//! each other up to date. As the bus may lose messages, every node also sends all its users
+//! with each heartbeat. Nodes that haven't been heard from in a while are considered gone,
+//! along with their users.
+//!
+//! Bans are shared the same way: they're sent to every node, and to starting nodes along
+//! with the users.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use piperchat_protocol::UserInfo;
+
+use crate::bus::Bus;
+use crate::commands::BanTarget;
+use crate::{pc, Command, PcMessage, Peer, Queue};
+
+const DIRECTORY: &str = "piperchat:directory";
+
+/// How often a node lets the others know it's still there, and which users it has
+pub(crate) const HEARTBEAT: Duration = Duration::from_secs(5);
+
+/// How long until a node that isn't heard from is considered gone
+pub(crate) const NODE_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Envelope {
+    /// The node that sent the message
+    pub(crate) node: String,
+    #[serde(flatten)]
+    pub(crate) message: BusMessage,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum BusMessage {
+    /// The sender doesn't know the recipients' users, they reply with `Users`
+    Hello,
+    /// All the users of the sender, in reply to `Hello` and as its heartbeat
+    Users {
+        users: Vec<UserInfo>,
+    },
+    Joined {
+        user: UserInfo,
+    },
+    Left {
+        id: u32,
+    },
+    Updated {
+        user: UserInfo,
+    },
+    /// A command for a user of the recipient
+    Deliver {
+        to: u32,
+        command: RemoteCommand,
+    },
+    /// Disconnect the user of the recipient called `name`
+    Kick {
+        name: String,
+        reason: String,
+    },
+    /// Keep `target` out for `duration` from now, replacing an earlier ban of theirs
+    Banned {
+        target: BanTarget,
+        duration: Duration,
+        reason: String,
+    },
+    Unbanned {
+        target: BanTarget,
+    },
+}
+
+/// A command for a connection on another node
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RemoteCommand {
+    SendMessage(PcMessage),
+    /// A call from the user `id` on the sending node
+    CallReceived {
+        id: u32,
+        name: String,
+    },
+    CallAccepted,
+    PeerHungup,
+    CallRejected(pc::CallResponseMessage),
+    CallCancelled,
+}
+
+impl RemoteCommand {
+    /// The command for the connection with `queue`, as sent by `node`
+    pub(crate) fn into_command(self, cluster: &Arc<Cluster>, node: &str, queue: &Queue) -> Command {
+        match self {
+            RemoteCommand::SendMessage(message) => Command::SendMessage(message),
+            RemoteCommand::CallReceived { id, name } => Command::CallReceived {
+                channel: Peer::Remote(RemotePeer {
+                    cluster: cluster.clone(),
+                    node: node.to_string(),
+                    id,
+                    user: queue.clone(),
+                }),
+                id,
+                name,
+            },
+            RemoteCommand::CallAccepted => Command::CallAccepted,
+            RemoteCommand::PeerHungup => Command::PeerHungup,
+            RemoteCommand::CallRejected(response) => Command::CallRejected(response),
+            RemoteCommand::CallCancelled => Command::CallCancelled,
+        }
+    }
+}
+
+/// This node's connection to the others
+#[derive(Debug)]
+pub(crate) struct Cluster {
+    pub(crate) node: String,
+    bus: Arc<dyn Bus>,
+}
+
+impl Cluster {
+    pub(crate) fn new(node: String, bus: Arc<dyn Bus>) -> Self {
+        Cluster { node, bus }
+    }
+
+    /// The directory's messages and those sent to this node
+    pub(crate) fn subscribe(&self) -> (mpsc::Receiver<Vec<u8>>, mpsc::Receiver<Vec<u8>>) {
+        (
+            self.bus.subscribe(DIRECTORY),
+            self.bus.subscribe(&node_channel(&self.node)),
+        )
+    }
+
+    /// Tell every node, this one included. What's lost is made up for by the heartbeats.
+    pub(crate) fn broadcast(&self, message: BusMessage) {
+        self.publish(DIRECTORY, message);
+    }
+
+    /// Tell `node`, false if the message is lost
+    pub(crate) fn send(&self, node: &str, message: BusMessage) -> bool {
+        self.publish(&node_channel(node), message)
+    }
+
+    fn publish(&self, channel: &str, message: BusMessage) -> bool {
+        let envelope = Envelope {
+            node: self.node.clone(),
+            message,
+        };
+        let payload = match serde_json::to_vec(&envelope) {
+            Ok(payload) => payload,
+            Err(err) => {
+                error!("can't serialize {envelope:?}: {err}");
+                return false;
+            }
+        };
+        let published = self.bus.publish(channel, payload);
+        if !published {
+            warn!("the bus is falling behind, dropped {envelope:?}");
+        }
+        published
+    }
+}
+
+/// A user on another node, as the peer in a call
+#[derive(Debug, Clone)]
+pub(crate) struct RemotePeer {
+    pub(crate) cluster: Arc<Cluster>,
+    pub(crate) node: String,
+    pub(crate) id: u32,
+    /// The queue of the user here in the call with them
+    pub(crate) user: Queue,
+}
+
+impl RemotePeer {
+    pub(crate) fn send(&self, command: Command) {
+        let command = match command {
+            Command::SendMessage(message) => RemoteCommand::SendMessage(message),
+            Command::CallReceived { id, name, .. } => RemoteCommand::CallReceived { id, name },
+            Command::CallAccepted => RemoteCommand::CallAccepted,
+            Command::PeerHungup => RemoteCommand::PeerHungup,
+            Command::CallRejected(response) => RemoteCommand::CallRejected(response),
+            Command::CallCancelled => RemoteCommand::CallCancelled,
+            Command::Kick(_) => {
+                warn!("can't kick {} on {}", self.id, self.node);
+                return;
+            }
+            // only told by the registry to the users of its node
+            Command::PeerGone => return,
+        };
+        let message = BusMessage::Deliver {
+            to: self.id,
+            command,
+        };
+        // the peer would be out of sync, so the call ends like when a local peer's queue
+        // overflows
+        if !self.cluster.send(&self.node, message) {
+            self.user.deliver(Command::PeerGone);
+        }
+    }
+}
+
+fn node_channel(node: &str) -> String {
+    format!("piperchat:node:{node}")
+}
+
+/// A name for a node that isn't given one, unlikely to be taken
+pub(crate) fn node_name() -> String {
+    static STARTED: AtomicU32 = AtomicU32::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format!(
+        "{}-{:x}-{}",
+        std::process::id(),
+        now.as_nanos() & 0xffff_ffff,
+        STARTED.fetch_add(1, Ordering::Relaxed)
+    )
+}
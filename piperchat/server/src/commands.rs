@@ -0,0 +1,244 @@
+//! Admin commands over a Unix socket, one per line. The first line has to be `token <token>`
+//! with the configured token, every line is answered with `ok` or `error: <reason>`:
+//!
+//! ```text
+//! kick <name> [reason]
+//! ban <name or IP address> <duration> [reason]
+//! unban <name or IP address>
+//! announce <text>
+//! ```
+//!
+//! Durations are a number with a unit, e.g. `90s`, `30m`, `12h` or `7d`. Kicks, bans and
+//! unbans apply to every node of the cluster, announcements to the users of this node. For
+//! example:
+//!
+//! ```sh
+//! printf 'token secret\nannounce Restarting at midnight\n' |
+//!     socat - UNIX-CONNECT:/run/piperchat/admin.sock
+//! ```
+
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+
+use crate::registry::Registry;
+use crate::{pc, PcMessage, Runtime};
+
+/// Who a ban applies to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BanTarget {
+    Name(String),
+    Address(IpAddr),
+}
+
+impl BanTarget {
+    fn parse(target: &str) -> Self {
+        match target.parse() {
+            Ok(address) => BanTarget::Address(address),
+            Err(_) => BanTarget::Name(target.to_string()),
+        }
+    }
+
+    pub(crate) fn matches(&self, name: &str, address: IpAddr) -> bool {
+        match self {
+            BanTarget::Name(banned) => banned == name,
+            BanTarget::Address(banned) => *banned == address,
+        }
+    }
+}
+
+impl fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanTarget::Name(name) => f.write_str(name),
+            BanTarget::Address(address) => write!(f, "{address}"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Ban {
+    pub(crate) target: BanTarget,
+    pub(crate) until: Instant,
+    pub(crate) reason: String,
+}
+
+#[derive(Debug)]
+enum AdminCommand {
+    Kick {
+        name: String,
+        reason: String,
+    },
+    Ban {
+        target: BanTarget,
+        duration: Duration,
+        reason: String,
+    },
+    Unban(BanTarget),
+    Announce(String),
+}
+
+impl AdminCommand {
+    fn parse(line: &str) -> Result<Self, String> {
+        let (command, arguments) = split(line.trim());
+        let required = |value: &str, what: &str| match value {
+            "" => Err(format!("missing {what}")),
+            value => Ok(value.to_string()),
+        };
+
+        match command {
+            "kick" => {
+                let (name, reason) = split(arguments);
+                Ok(AdminCommand::Kick {
+                    name: required(name, "name")?,
+                    reason: reason.to_string(),
+                })
+            }
+            "ban" => {
+                let (target, rest) = split(arguments);
+                let (duration, reason) = split(rest);
+                Ok(AdminCommand::Ban {
+                    target: BanTarget::parse(&required(target, "name or address")?),
+                    duration: parse_duration(&required(duration, "duration")?)?,
+                    reason: reason.to_string(),
+                })
+            }
+            "unban" => {
+                let target = required(arguments, "name or address")?;
+                Ok(AdminCommand::Unban(BanTarget::parse(&target)))
+            }
+            "announce" => Ok(AdminCommand::Announce(required(arguments, "text")?)),
+            _ => Err(format!("unknown command {command:?}")),
+        }
+    }
+
+    async fn run(self, registry: &Registry) -> Result<(), String> {
+        match self {
+            AdminCommand::Kick { name, reason } => {
+                if !registry.kick(name.clone(), reason.clone()).await {
+                    return Err(format!("no user {name}"));
+                }
+                info!("kicked {name}: {reason}");
+            }
+            AdminCommand::Ban {
+                target,
+                duration,
+                reason,
+            } => {
+                let until = Instant::now()
+                    .checked_add(duration)
+                    .ok_or_else(|| format!("duration {duration:?} is too long"))?;
+                info!("banning {target} for {duration:?}: {reason}");
+                registry
+                    .ban(Ban {
+                        target,
+                        until,
+                        reason,
+                    })
+                    .await;
+            }
+            AdminCommand::Unban(target) => {
+                if !registry.unban(target.clone()).await {
+                    return Err(format!("{target} isn't banned"));
+                }
+                info!("unbanned {target}");
+            }
+            AdminCommand::Announce(text) => {
+                info!("announcing {text:?}");
+                let message = PcMessage::Announcement(pc::AnnouncementMessage { text });
+                registry.announce(message).await;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// the first word and the rest
+fn split(text: &str) -> (&str, &str) {
+    let (first, rest) = text.split_once(' ').unwrap_or((text, ""));
+    (first, rest.trim())
+}
+
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid duration {duration:?}, expected e.g. 30m");
+    let unit = duration.chars().last().ok_or_else(invalid)?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = duration[..duration.len() - 1]
+        .parse()
+        .map_err(|_| invalid())?;
+    let seconds = count
+        .checked_mul(seconds)
+        .ok_or_else(|| format!("duration {duration:?} is too long"))?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Take commands on `listener` until an error occurs
+pub(crate) async fn serve(
+    listener: UnixListener,
+    registry: Registry,
+    runtime: watch::Receiver<Arc<Runtime>>,
+) -> color_eyre::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let registry = registry.clone();
+        // a reload takes effect with the next connection
+        let token = runtime.borrow().config.admin.token.clone();
+        tokio::spawn(async move {
+            if let Err(err) = session(stream, registry, token).await {
+                error!("admin session failed: {err:#}");
+            }
+        });
+    }
+}
+
+async fn session(
+    stream: UnixStream,
+    registry: Registry,
+    token: Option<String>,
+) -> color_eyre::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let authenticated = match (lines.next_line().await?, token) {
+        (Some(line), Some(token)) => line.strip_prefix("token ") == Some(token.as_str()),
+        _ => false,
+    };
+    if !authenticated {
+        writer.write_all(b"error: wrong token\n").await?;
+        return Ok(());
+    }
+    writer.write_all(b"ok\n").await?;
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let result = match AdminCommand::parse(&line) {
+            Ok(command) => command.run(&registry).await,
+            Err(err) => Err(err),
+        };
+        let reply = match result {
+            Ok(()) => "ok\n".to_string(),
+            Err(err) => format!("error: {err}\n"),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+
+    Ok(())
+}
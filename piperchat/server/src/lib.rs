@@ -0,0 +1,811 @@
+//! The signalling server: keeps track of connected users and relays call setup between them.
+
+use color_eyre::eyre::{bail, WrapErr};
+use futures::{SinkExt, StreamExt};
+use log::{error, info};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
+    select,
+    sync::{mpsc, watch, Notify},
+    task::JoinHandle,
+    time::{timeout, Sleep},
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::server::{Request, Response},
+    protocol::{CloseFrame, WebSocketConfig},
+};
+
+use piperchat_protocol as pc;
+use piperchat_protocol::call::{CallEvent, CallState, Phase};
+use piperchat_protocol::Presence;
+
+mod admin;
+mod auth;
+pub mod bus;
+mod cluster;
+mod commands;
+pub mod config;
+mod limits;
+mod metrics;
+pub mod redis;
+mod registry;
+mod tls;
+
+use auth::Auth;
+use bus::{Bus, MemoryBus};
+use cluster::{Cluster, RemotePeer};
+use config::BusConfig;
+pub use config::Config;
+use limits::{Connections, RateLimiter, Violation};
+use metrics::{Metrics, Rejection};
+use registry::{Registry, User};
+
+type WsMessage = tungstenite::Message;
+type PcMessage = pc::Message;
+
+#[derive(Debug)]
+enum Command {
+    SendMessage(PcMessage),
+    CallReceived {
+        channel: Peer,
+        id: u32,
+        name: String,
+    },
+    CallAccepted,
+    PeerHungup,
+    CallRejected(pc::CallResponseMessage),
+    CallCancelled,
+    /// The peer can't be reached anymore without having ended the call, as their node is
+    /// gone or the bus is falling behind
+    PeerGone,
+    /// Disconnect the user, with the reason given by the admin
+    Kick(String),
+}
+
+// The queue of commands for a connection. Dropping commands would leave its client out of
+// sync, so a connection that lets its queue fill up is disconnected instead.
+#[derive(Debug, Clone)]
+struct Queue {
+    tx: mpsc::Sender<Command>,
+    overflowed: Arc<Notify>,
+}
+
+impl Queue {
+    fn new(size: usize) -> (Self, mpsc::Receiver<Command>) {
+        let (tx, rx) = mpsc::channel(size);
+        let queue = Queue {
+            tx,
+            overflowed: Arc::new(Notify::new()),
+        };
+        (queue, rx)
+    }
+
+    // Queue `command` without waiting
+    fn deliver(&self, command: Command) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(command) {
+            self.overflowed.notify_one();
+        }
+    }
+
+    // Relay a message from a client to its peer, waiting for room in the peer's queue. This
+    // keeps the client's messages from piling up, as they aren't read in the meantime.
+    async fn relay(&self, command: Command) {
+        if timeout(RELAY_TIMEOUT, self.tx.send(command)).await.is_err() {
+            self.overflowed.notify_one();
+        }
+    }
+}
+
+// the connection task of a call's peer, on this node or another one
+#[derive(Debug, Clone)]
+enum Peer {
+    Local(Queue),
+    Remote(RemotePeer),
+}
+
+impl Peer {
+    fn deliver(&self, command: Command) {
+        match self {
+            Peer::Local(queue) => queue.deliver(command),
+            Peer::Remote(peer) => peer.send(command),
+        }
+    }
+
+    async fn relay(&self, command: Command) {
+        match self {
+            Peer::Local(queue) => queue.relay(command).await,
+            Peer::Remote(peer) => peer.send(command),
+        }
+    }
+
+    // the node the peer is on, none for this one
+    fn node(&self) -> Option<String> {
+        match self {
+            Peer::Local(_) => None,
+            Peer::Remote(peer) => Some(peer.node.clone()),
+        }
+    }
+}
+
+// How long relaying a message waits for room in the peer's queue
+const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long sending a message waits for the client to make room for it
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a client disconnected for breaking the limits has to close the connection
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The configuration along with what's loaded from the files it names, replaced as a whole on
+// every reload
+struct Runtime {
+    config: Config,
+    tls: Option<TlsAcceptor>,
+    auth: Auth,
+}
+
+impl Runtime {
+    fn new(config: Config) -> color_eyre::Result<Self> {
+        let tls = config.tls.as_ref().map(tls::acceptor).transpose()?;
+        let auth = Auth::new(&config.auth)?;
+
+        Ok(Runtime { config, tls, auth })
+    }
+}
+
+/// A running server, which can be reconfigured without disconnecting its users
+pub struct Server {
+    registry: Registry,
+    metrics: Arc<Metrics>,
+    connections: Arc<Connections>,
+    runtime: watch::Sender<Arc<Runtime>>,
+    // tasks accepting clients on the configured addresses
+    listeners: HashMap<SocketAddr, JoinHandle<()>>,
+    admin: Option<(SocketAddr, JoinHandle<()>)>,
+    commands: Option<(PathBuf, JoinHandle<()>)>,
+}
+
+impl Server {
+    /// Fails if the files named by `config` can't be loaded. Has to be called within a Tokio
+    /// runtime.
+    pub fn new(config: Config) -> color_eyre::Result<Self> {
+        let bus: Arc<dyn Bus> = match &config.cluster.bus {
+            BusConfig::Memory => Arc::new(MemoryBus::default()),
+            BusConfig::Redis { address, password } => {
+                Arc::new(redis::RedisBus::new(address.clone(), password.clone()))
+            }
+        };
+        Self::with_bus(config, bus)
+    }
+
+    /// A server reaching the other nodes of its cluster over `bus` instead of the configured one
+    pub fn with_bus(config: Config, bus: Arc<dyn Bus>) -> color_eyre::Result<Self> {
+        let node = config
+            .cluster
+            .node
+            .clone()
+            .unwrap_or_else(cluster::node_name);
+        let (runtime, _) = watch::channel(Arc::new(Runtime::new(config)?));
+        info!("joining the cluster as {node}");
+
+        Ok(Server {
+            registry: Registry::spawn(Arc::new(Cluster::new(node, bus))),
+            metrics: Arc::new(Metrics::new()),
+            connections: Arc::default(),
+            runtime,
+            listeners: HashMap::new(),
+            admin: None,
+            commands: None,
+        })
+    }
+
+    pub fn config(&self) -> Config {
+        self.runtime.borrow().config.clone()
+    }
+
+    /// Switch to `config`. When it can't be loaded, the current configuration stays.
+    /// Connected users stay connected, call `listen` to apply changed listen addresses.
+    pub fn reload(&self, config: Config) -> color_eyre::Result<()> {
+        let runtime = Runtime::new(config)?;
+        self.runtime.send_replace(Arc::new(runtime));
+        Ok(())
+    }
+
+    /// Listen on the configured addresses and stop listening on those no longer configured
+    pub async fn listen(&mut self) -> color_eyre::Result<()> {
+        let (addresses, admin, socket) = {
+            let config = &self.runtime.borrow().config;
+            (
+                config.listen.clone(),
+                config.admin.listen,
+                config.admin.socket.clone(),
+            )
+        };
+
+        if self.commands.as_ref().map(|(path, _)| path) != socket.as_ref() {
+            if let Some((path, task)) = self.commands.take() {
+                info!("no longer taking admin commands on {}", path.display());
+                task.abort();
+                std::fs::remove_file(&path).ok();
+            }
+            if let Some(path) = socket {
+                let listener = bind_socket(&path)
+                    .wrap_err_with(|| format!("can't listen on {}", path.display()))?;
+                info!("taking admin commands on {}", path.display());
+                self.commands = Some((path, self.spawn_commands(listener)));
+            }
+        }
+
+        if self.admin.as_ref().map(|(address, _)| *address) != admin {
+            if let Some((address, task)) = self.admin.take() {
+                info!("no longer serving the admin endpoint on {address}");
+                task.abort();
+            }
+            if let Some(address) = admin {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .wrap_err_with(|| format!("can't listen on {address}"))?;
+                info!("serving the admin endpoint on {address}");
+                self.admin = Some((address, self.spawn_admin(listener)));
+            }
+        }
+
+        self.listeners.retain(|address, listener| {
+            let keep = addresses.contains(address);
+            if !keep {
+                info!("no longer listening on {address}");
+                listener.abort();
+            }
+            keep
+        });
+
+        for address in addresses {
+            if self.listeners.contains_key(&address) {
+                continue;
+            }
+            let listener = TcpListener::bind(address)
+                .await
+                .wrap_err_with(|| format!("can't listen on {address}"))?;
+            info!("listening on {address}");
+            let task = self.spawn(listener);
+            self.listeners.insert(address, task);
+        }
+
+        Ok(())
+    }
+
+    /// Accept clients on `listener` until an error occurs
+    pub fn spawn(&self, listener: TcpListener) -> JoinHandle<()> {
+        let accepting = accept(
+            listener,
+            self.registry.clone(),
+            self.metrics.clone(),
+            self.connections.clone(),
+            self.runtime.subscribe(),
+        );
+        tokio::spawn(async move {
+            if let Err(err) = accepting.await {
+                error!("can't accept clients: {err:#}");
+            }
+        })
+    }
+
+    /// Take admin commands on `listener` until an error occurs
+    pub fn spawn_commands(&self, listener: UnixListener) -> JoinHandle<()> {
+        let serving = commands::serve(listener, self.registry.clone(), self.runtime.subscribe());
+        tokio::spawn(async move {
+            if let Err(err) = serving.await {
+                error!("admin socket failed: {err:#}");
+            }
+        })
+    }
+
+    /// Serve the admin endpoint on `listener` until an error occurs
+    pub fn spawn_admin(&self, listener: TcpListener) -> JoinHandle<()> {
+        let serving = admin::serve(listener, self.registry.clone(), self.metrics.clone());
+        tokio::spawn(async move {
+            if let Err(err) = serving.await {
+                error!("admin endpoint failed: {err:#}");
+            }
+        })
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.commands {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+// Bind a Unix socket only the server's user can connect to, replacing a stale one
+fn bind_socket(path: &Path) -> color_eyre::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+/// Accept clients on `listener` until an error occurs
+pub async fn serve(listener: TcpListener, config: Config) -> color_eyre::Result<()> {
+    let server = Server::new(config)?;
+    accept(
+        listener,
+        server.registry.clone(),
+        server.metrics.clone(),
+        server.connections.clone(),
+        server.runtime.subscribe(),
+    )
+    .await
+}
+
+async fn accept(
+    listener: TcpListener,
+    registry: Registry,
+    metrics: Arc<Metrics>,
+    connections: Arc<Connections>,
+    runtime: watch::Receiver<Arc<Runtime>>,
+) -> color_eyre::Result<()> {
+    loop {
+        let (socket, address) = listener.accept().await?;
+        // signalling messages are small and shouldn't wait for each other
+        socket.set_nodelay(true).ok();
+        let max_connections = runtime.borrow().config.limits.max_connections_per_ip;
+        let slot = match connections.open(address.ip(), max_connections) {
+            Some(slot) => slot,
+            None => {
+                info!("too many connections from {}", address.ip());
+                metrics.rejected(Rejection::TooManyConnections);
+                continue;
+            }
+        };
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        let runtime = runtime.clone();
+        let tls = runtime.borrow().tls.clone();
+        tokio::spawn(async move {
+            let result = match tls {
+                Some(tls) => match tls.accept(socket).await {
+                    Ok(stream) => process(stream, address, registry, metrics, runtime).await,
+                    Err(err) => Err(err.into()),
+                },
+                None => process(socket, address, registry, metrics, runtime).await,
+            };
+            if let Err(err) = result {
+                error!("connection from {address} failed: {err:#}");
+            }
+            drop(slot);
+        });
+    }
+}
+
+async fn process<S>(
+    stream: S,
+    address: SocketAddr,
+    registry: Registry,
+    metrics: Arc<Metrics>,
+    runtime: watch::Receiver<Arc<Runtime>>,
+) -> color_eyre::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // the token is passed in the query of the URL
+    let mut token = None;
+    let (max_message_size, queue_size) = {
+        let limits = &runtime.borrow().config.limits;
+        (limits.max_message_size, limits.queue_size)
+    };
+    let ws_config = WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..WebSocketConfig::default()
+    };
+    // the callback's error type is up to tungstenite
+    #[allow(clippy::result_large_err)]
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(
+        stream,
+        |request: &Request, response: Response| {
+            token = request.uri().query().and_then(auth::token_from_query);
+            Ok(response)
+        },
+        Some(ws_config),
+    )
+    .await?;
+    let (mut ws_sink, mut ws_stream) = ws.split();
+
+    let message = read_message(&mut ws_stream).await?;
+    metrics.received(&message);
+    let connect_message = if let PcMessage::Connect(connect_message) = message {
+        connect_message
+    } else {
+        bail!("Expected ConnectMessage, got: {message:?}");
+    };
+
+    let name = connect_message.name;
+    let id = connect_message.id;
+
+    // construct user
+    let (queue, mut rx) = Queue::new(queue_size);
+    let user = User {
+        name: name.clone(),
+        id,
+        presence: Presence::Available,
+        status: String::new(),
+        address: address.ip(),
+        tx: queue.clone(),
+        phase: Phase::Idle,
+        peer: None,
+        caller: false,
+    };
+
+    let (auth, max_users) = {
+        let runtime = runtime.borrow();
+        let auth = runtime.auth.check(&name, token.as_deref());
+        (auth, runtime.config.limits.max_users)
+    };
+    let registered = match auth {
+        Ok(()) => registry.register(user, max_users).await,
+        Err(reason) => Err((Rejection::Auth, reason)),
+    };
+    if let Err((rejection, reason)) = registered {
+        metrics.rejected(rejection);
+        send_message(
+            &mut ws_sink,
+            &metrics,
+            &PcMessage::ConnectResponse(pc::ConnectResponse::Reject(reason)),
+        )
+        .await?;
+        bail!("User didn't connect");
+    }
+    info!("{id} connected.");
+
+    let mut client_state: CallState<Peer> = CallState::Idle;
+    // what the client did wrong, if it's disconnected for it
+    let mut violation = None;
+    // the session ends early on errors, the user is unregistered and their call ended either way
+    let result: color_eyre::Result<()> = async {
+        // the user list is already queued, so it follows these
+        let ice_servers = runtime.borrow().config.ice.message();
+        let mut welcome = vec![PcMessage::ConnectResponse(pc::ConnectResponse::Accept)];
+        welcome.extend(ice_servers.map(PcMessage::IceServers));
+        for message in &welcome {
+            send_message(&mut ws_sink, &metrics, message).await?;
+        }
+
+        // running only while our call is ringing on the peer's side
+        let mut ring_timer: Option<Pin<Box<Sleep>>> = None;
+        // who the call is with, and when we dialed
+        let mut peer_id = None;
+        let mut dialed = None;
+        let mut phase = Phase::Idle;
+        let mut limiter = RateLimiter::new(runtime.borrow().config.limits.message_burst);
+
+        loop {
+            // let the registry know about the changes since the last round
+            if client_state.phase() != phase {
+                let previous = phase;
+                phase = client_state.phase();
+                if phase == Phase::Requested {
+                    dialed = Some(Instant::now());
+                }
+                if let (Phase::Requested, Phase::InCall, Some(dialed)) = (previous, phase, dialed) {
+                    metrics.call_set_up(dialed.elapsed().as_secs_f64());
+                }
+                if phase == Phase::Idle {
+                    peer_id = None;
+                    dialed = None;
+                }
+                registry
+                    .set_call(id, phase, peer_id.clone(), dialed.is_some())
+                    .await;
+            }
+
+            match client_state {
+                CallState::Requested(_) => {
+                    ring_timer.get_or_insert_with(|| {
+                        let ring_timeout = runtime.borrow().config.limits.ring_timeout;
+                        Box::pin(tokio::time::sleep(ring_timeout))
+                    });
+                }
+                _ => ring_timer = None,
+            }
+
+            select! {
+                // the client isn't taking what it's sent fast enough
+                _ = queue.overflowed.notified() => {
+                    violation = Some(Violation::SlowClient);
+                    break;
+                }
+
+                command = rx.recv() => {
+                    // the peer's messages are only passed on if they fit the state of the call
+                    let (event, message) = match command {
+                        Some(Command::SendMessage(message)) => {
+                            send_message(&mut ws_sink, &metrics, &message).await?;
+                            continue;
+                        }
+                        Some(Command::CallReceived{ channel: peer_sink, id: caller, name }) => {
+                            if let Err(err) = client_state.handle(CallEvent::Incoming(peer_sink.clone())) {
+                                info!("{id} can't take a call from {name}: {err}");
+                                peer_sink.deliver(Command::CallRejected(pc::CallResponseMessage::Reject));
+                                continue;
+                            }
+                            peer_id = Some((peer_sink.node(), caller));
+                            (None, PcMessage::CallReceived(pc::CallReceivedMessage { name }))
+                        },
+                        Some(Command::CallAccepted) => {
+                            (Some(CallEvent::Accepted), PcMessage::CallResponse(pc::CallResponseMessage::Accept))
+                        },
+                        Some(Command::PeerHungup) => (Some(CallEvent::PeerHangup), PcMessage::CallHangup),
+                        Some(Command::CallRejected(response)) => {
+                            (Some(CallEvent::Rejected), PcMessage::CallResponse(response))
+                        }
+                        Some(Command::CallCancelled) => (Some(CallEvent::PeerHangup), PcMessage::CallCancelled),
+                        // told the way the peer would have in the phase the call is in
+                        Some(Command::PeerGone) => match client_state.phase() {
+                            Phase::Requested => {
+                                (Some(CallEvent::Rejected), PcMessage::CallResponse(pc::CallResponseMessage::Reject))
+                            }
+                            Phase::Received => (Some(CallEvent::PeerHangup), PcMessage::CallCancelled),
+                            _ => (Some(CallEvent::PeerHangup), PcMessage::CallHangup),
+                        },
+                        Some(Command::Kick(reason)) => {
+                            info!("{id} was kicked: {reason}");
+                            let message = PcMessage::Kicked(pc::KickedMessage { reason });
+                            send_message(&mut ws_sink, &metrics, &message).await?;
+                            ws_sink.close().await.ok();
+                            break;
+                        }
+                        None => break,
+                    };
+
+                    if let Some(event) = event {
+                        if let Err(err) = client_state.handle(event) {
+                            info!("ignoring peer message for {id}: {err}");
+                            continue;
+                        }
+                    }
+                    send_message(&mut ws_sink, &metrics, &message).await?;
+                },
+
+                // the peer didn't answer our call in time
+                _ = async { ring_timer.as_mut().unwrap().await }, if ring_timer.is_some() => {
+                    if let Ok(Some(peer)) = client_state.handle(CallEvent::Rejected) {
+                        info!("call from {id} wasn't answered in time");
+                        peer.deliver(Command::CallCancelled);
+                        let message = PcMessage::CallResponse(pc::CallResponseMessage::NoAnswer);
+                        send_message(&mut ws_sink, &metrics, &message).await?;
+                    }
+                },
+
+                // message from user socket
+                message = ws_stream.next() => {
+                    let limits = runtime.borrow().config.limits.clone();
+                    if !limiter.allow(limits.messages_per_second, limits.message_burst) {
+                        violation = Some(Violation::RateLimit);
+                        break;
+                    }
+
+                    match message {
+                        Some(Ok(WsMessage::Text(message))) => {
+                            info!("received: {message:?}");
+                            let message: PcMessage = match serde_json::from_str(&message) {
+                                Ok(message) => message,
+                                Err(err) => {
+                                    info!("invalid message from {id}: {err}");
+                                    violation = Some(Violation::InvalidMessage);
+                                    break;
+                                }
+                            };
+                            metrics.received(&message);
+                            if let PcMessage::Webrtc(pc::WebrtcMsg::Sdp { sdp, .. }) = &message {
+                                if sdp.len() > limits.max_sdp_size {
+                                    violation = Some(Violation::SdpTooLarge);
+                                    break;
+                                }
+                            }
+
+                            let result = match message {
+                                // presence can be changed regardless of the call state
+                                PcMessage::SetPresence(presence) => {
+                                    registry.set_presence(id, presence).await;
+                                    Ok(())
+                                }
+                                // sent after the hangup, so it arrives in any state
+                                PcMessage::CallStats(stats) => {
+                                    info!(
+                                        "{id} call summary: {}s, {} bytes sent, {} bytes received, {} packets lost, jitter {:.1}ms, rtt {}",
+                                        stats.duration,
+                                        stats.bytes_sent,
+                                        stats.bytes_received,
+                                        stats.packets_lost,
+                                        stats.jitter * 1000.0,
+                                        stats.round_trip_time.map_or("unknown".to_string(), |rtt| format!("{:.0}ms", rtt * 1000.0)),
+                                    );
+                                    Ok(())
+                                }
+                                PcMessage::Call(call_message) => {
+                                    let session_id = call_message.peer;
+                                    if session_id == id {
+                                        error!("Can't call self!");
+                                        break;
+                                    }
+                                    info!("{id} requested call with {session_id}");
+
+                                    let peer_tx = match registry.lookup(session_id, queue.clone()).await {
+                                        Some((Presence::DoNotDisturb, _)) => {
+                                            info!("{session_id} is in do-not-disturb mode, rejecting call from {id}");
+                                            Err(pc::CallResponseMessage::DoNotDisturb)
+                                        }
+                                        Some((_, peer)) => Ok(peer),
+                                        None => {
+                                            info!("{id} called {session_id}, who isn't connected");
+                                            Err(pc::CallResponseMessage::Reject)
+                                        }
+                                    };
+                                    match peer_tx {
+                                        Ok(peer_tx) => client_state.handle(CallEvent::Dial(peer_tx.clone())).map(|_| {
+                                            peer_id = Some((peer_tx.node(), session_id));
+                                            peer_tx.deliver(Command::CallReceived{channel: Peer::Local(queue.clone()), id, name: name.clone()});
+                                        }),
+                                        Err(response) => {
+                                            queue.deliver(Command::SendMessage(PcMessage::CallResponse(response)));
+                                            Ok(())
+                                        }
+                                    }
+                                }
+                                // let the peer know we accepted
+                                PcMessage::CallResponse(pc::CallResponseMessage::Accept) => {
+                                    client_state.handle(CallEvent::Accept).map(|_| {
+                                        if let Some(peer) = client_state.peer() {
+                                            peer.deliver(Command::CallAccepted);
+                                        }
+                                    })
+                                }
+                                // any other response is a rejection, the other outcomes are only
+                                // for the server to tell
+                                PcMessage::CallResponse(_) => {
+                                    client_state.handle(CallEvent::Reject).map(|peer| {
+                                        if let Some(peer) = peer {
+                                            peer.deliver(Command::CallRejected(pc::CallResponseMessage::Reject));
+                                        }
+                                    })
+                                }
+                                PcMessage::CallHangup => {
+                                    client_state.handle(CallEvent::Hangup).map(|peer| {
+                                        if let Some(peer) = peer {
+                                            peer.deliver(Command::PeerHungup);
+                                        }
+                                    })
+                                }
+                                PcMessage::Webrtc(_) | PcMessage::Recording(_) => {
+                                    match &client_state {
+                                        CallState::InCall(peer) => {
+                                            peer.relay(Command::SendMessage(message)).await;
+                                            Ok(())
+                                        }
+                                        _ => {
+                                            error!("call message from user {id} while not in a call");
+                                            Ok(())
+                                        }
+                                    }
+                                }
+                                message => {
+                                    error!("Wrong message from user {id}: {message:?}");
+                                    Ok(())
+                                }
+                            };
+
+                            if let Err(err) = result {
+                                error!("Wrong message from user {id}: {err}");
+                            }
+                        },
+                        Some(Err(tungstenite::Error::Capacity(err))) => {
+                            info!("too large message from {id}: {err}");
+                            violation = Some(Violation::MessageTooLarge);
+                            break;
+                        }
+                        Some(Err(err)) => {
+                            error!("client={}, error={}", id, err);
+                            break;
+                        }
+                        None => break,
+                        _ => ()
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+    .await;
+
+    // end the call we're in, if any
+    let phase = client_state.phase();
+    if let Ok(Some(peer)) = client_state.handle(CallEvent::Disconnect) {
+        let command = match phase {
+            Phase::Received => Command::CallRejected(pc::CallResponseMessage::Reject),
+            _ => Command::PeerHungup,
+        };
+        peer.deliver(command);
+    }
+
+    info!("{id} disconnected.");
+
+    registry.unregister(id).await;
+
+    if let Some(violation) = violation {
+        info!("{id} broke the limits: {}", violation.reason());
+        metrics.violation(violation);
+        let reason = violation.reason().to_string();
+        let message = PcMessage::ProtocolError(pc::ProtocolErrorMessage {
+            reason: reason.clone(),
+        });
+        let close = WsMessage::Close(Some(CloseFrame {
+            code: violation.close_code(),
+            reason: reason.into(),
+        }));
+        // a client that isn't reading may not take these either
+        let farewell = async {
+            send_message(&mut ws_sink, &metrics, &message).await.ok();
+            ws_sink.send(close).await.ok();
+        };
+        timeout(SEND_TIMEOUT, farewell).await.ok();
+        // wait for the client to close too, so it gets to read the error
+        let closed = async { while let Some(Ok(_)) = ws_stream.next().await {} };
+        timeout(CLOSE_TIMEOUT, closed).await.ok();
+    }
+
+    result
+}
+
+async fn read_message<S>(ws_stream: &mut S) -> color_eyre::Result<PcMessage>
+where
+    S: StreamExt<Item = tungstenite::Result<WsMessage>> + Unpin,
+{
+    let message = if let Some(Ok(WsMessage::Text(payload))) = ws_stream.next().await {
+        payload
+    } else {
+        bail!("Received WebSocket Frame is not a text frame")
+    };
+    let message: PcMessage = serde_json::from_str(&message)?;
+    info!("received: {message:?}");
+
+    Ok(message)
+}
+
+async fn send_message<S>(
+    ws_sink: &mut S,
+    metrics: &Metrics,
+    message: &PcMessage,
+) -> color_eyre::Result<()>
+where
+    S: SinkExt<WsMessage, Error = tungstenite::Error> + Unpin,
+{
+    info!("sending: {message:?}");
+    metrics.sent(message);
+    let message = serde_json::to_string(&message)?;
+    match timeout(SEND_TIMEOUT, ws_sink.send(WsMessage::text(&message))).await {
+        Ok(result) => result?,
+        Err(_) => bail!("the client isn't reading"),
+    }
+
+    Ok(())
+}
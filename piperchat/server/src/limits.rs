@@ -0,0 +1,123 @@
+//! Enforcement of the configured limits on what a single client can do.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+/// Something a client did that gets it disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Violation {
+    RateLimit,
+    MessageTooLarge,
+    SdpTooLarge,
+    InvalidMessage,
+    /// Not reading what it's sent, so its queue filled up
+    SlowClient,
+}
+
+impl Violation {
+    /// Label of the metric
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Violation::RateLimit => "rate_limit",
+            Violation::MessageTooLarge => "message_too_large",
+            Violation::SdpTooLarge => "sdp_too_large",
+            Violation::InvalidMessage => "invalid_message",
+            Violation::SlowClient => "slow_client",
+        }
+    }
+
+    /// What the client is told
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Violation::RateLimit => "Too many messages",
+            Violation::MessageTooLarge => "Message too large",
+            Violation::SdpTooLarge => "Session description too large",
+            Violation::InvalidMessage => "Invalid message",
+            Violation::SlowClient => "Not keeping up with the messages sent",
+        }
+    }
+
+    pub(crate) fn close_code(&self) -> CloseCode {
+        match self {
+            Violation::MessageTooLarge | Violation::SdpTooLarge => CloseCode::Size,
+            Violation::RateLimit | Violation::InvalidMessage | Violation::SlowClient => {
+                CloseCode::Policy
+            }
+        }
+    }
+}
+
+/// Token bucket: allows bursts of messages, as long as the average rate stays low enough
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(burst: u32) -> Self {
+        RateLimiter {
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token for a message, if there's one left. The limits are passed on every call
+    /// so a reload applies to connected clients too.
+    pub(crate) fn allow(&mut self, per_second: f64, burst: u32) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refill).min(burst as f64);
+        self.last = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Open connections by address
+#[derive(Debug, Default)]
+pub(crate) struct Connections(Mutex<HashMap<IpAddr, usize>>);
+
+impl Connections {
+    /// Count a connection from `address`, unless there are `max` of them already. The
+    /// connection counts until the returned slot is dropped.
+    pub(crate) fn open(self: &Arc<Self>, address: IpAddr, max: usize) -> Option<ConnectionSlot> {
+        let mut connections = self.0.lock().unwrap();
+        let count = connections.entry(address).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionSlot {
+            connections: self.clone(),
+            address,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ConnectionSlot {
+    connections: Arc<Connections>,
+    address: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.0.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.address);
+            }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use clap::Parser;
+use log::{error, info};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+
+use piperchat_server::{Config, Server};
+
+#[derive(Debug, clap::Parser)]
+struct Args {
+    /// Configuration file, reloaded on SIGHUP
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// How long a call can ring before the server cancels it, in seconds. Overrides the
+    /// configuration file.
+    #[arg(long)]
+    ring_timeout: Option<u64>,
+}
+
+impl Args {
+    fn config(&self) -> color_eyre::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(ring_timeout) = self.ring_timeout {
+            config.limits.ring_timeout = Duration::from_secs(ring_timeout);
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+#[tokio::main]
+async fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+    let args = Args::parse();
+    let config = args.config()?;
+
+    // RUST_LOG wins over the configured level
+    let rust_log = std::env::var("RUST_LOG").ok();
+    let mut logger = pretty_env_logger::formatted_builder();
+    match &rust_log {
+        Some(filters) => logger.parse_filters(filters),
+        None => logger.filter_level(log::LevelFilter::Trace),
+    };
+    logger.try_init()?;
+    if rust_log.is_none() {
+        log::set_max_level(config.log.level);
+    }
+
+    let mut server = Server::new(config)?;
+    server.listen().await?;
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("reloading the configuration");
+        let config = match args.config() {
+            Ok(config) => config,
+            Err(err) => {
+                error!("keeping the current configuration: {err:#}");
+                continue;
+            }
+        };
+        let level = config.log.level;
+        if let Err(err) = server.reload(config) {
+            error!("keeping the current configuration: {err:#}");
+            continue;
+        }
+        if rust_log.is_none() {
+            log::set_max_level(level);
+        }
+        if let Err(err) = server.listen().await {
+            error!("{err:#}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+
+use piperchat_protocol::call::Phase;
+
+use crate::limits::Violation;
+use crate::registry::User;
+use crate::PcMessage;
+
+/// Why a user wasn't let in
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Rejection {
+    Auth,
+    NameTaken,
+    IdTaken,
+    Full,
+    Banned,
+    TooManyConnections,
+}
+
+impl Rejection {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::Auth => "auth",
+            Rejection::NameTaken => "name_taken",
+            Rejection::IdTaken => "id_taken",
+            Rejection::Full => "full",
+            Rejection::Banned => "banned",
+            Rejection::TooManyConnections => "too_many_connections",
+        }
+    }
+}
+
+/// Prometheus metrics of a server. The gauges are computed from the users when scraped.
+pub(crate) struct Metrics {
+    registry: Registry,
+    connected_users: IntGauge,
+    active_calls: IntGauge,
+    call_setup: Histogram,
+    messages: IntCounterVec,
+    rejected_connects: IntCounterVec,
+    violations: IntCounterVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        let connected_users =
+            IntGauge::new("piperchat_connected_users", "Users logged in").unwrap();
+        let active_calls = IntGauge::new(
+            "piperchat_active_calls",
+            "Calls that were accepted, placed from here",
+        )
+        .unwrap();
+        let call_setup = Histogram::with_opts(
+            HistogramOpts::new(
+                "piperchat_call_setup_seconds",
+                "Time from dialing until the peer accepts",
+            )
+            .buckets(vec![0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0]),
+        )
+        .unwrap();
+        let messages = IntCounterVec::new(
+            Opts::new("piperchat_messages_total", "Protocol messages by type"),
+            &["direction", "type"],
+        )
+        .unwrap();
+        let rejected_connects = IntCounterVec::new(
+            Opts::new(
+                "piperchat_rejected_connects_total",
+                "Users that weren't let in",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let violations = IntCounterVec::new(
+            Opts::new(
+                "piperchat_violations_total",
+                "Clients disconnected for breaking the limits",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry
+            .register(Box::new(connected_users.clone()))
+            .unwrap();
+        registry.register(Box::new(active_calls.clone())).unwrap();
+        registry.register(Box::new(call_setup.clone())).unwrap();
+        registry.register(Box::new(messages.clone())).unwrap();
+        registry
+            .register(Box::new(rejected_connects.clone()))
+            .unwrap();
+        registry.register(Box::new(violations.clone())).unwrap();
+
+        Metrics {
+            registry,
+            connected_users,
+            active_calls,
+            call_setup,
+            messages,
+            rejected_connects,
+            violations,
+        }
+    }
+
+    pub(crate) fn received(&self, message: &PcMessage) {
+        self.messages
+            .with_label_values(&["received", message_type(message)])
+            .inc();
+    }
+
+    pub(crate) fn sent(&self, message: &PcMessage) {
+        self.messages
+            .with_label_values(&["sent", message_type(message)])
+            .inc();
+    }
+
+    pub(crate) fn rejected(&self, rejection: Rejection) {
+        self.rejected_connects
+            .with_label_values(&[rejection.as_str()])
+            .inc();
+    }
+
+    pub(crate) fn violation(&self, violation: Violation) {
+        self.violations
+            .with_label_values(&[violation.as_str()])
+            .inc();
+    }
+
+    /// An accepted call took `seconds` to set up
+    pub(crate) fn call_set_up(&self, seconds: f64) {
+        self.call_setup.observe(seconds);
+    }
+
+    /// The metrics in the Prometheus text format
+    pub(crate) fn encode(&self, users: &[User]) -> String {
+        // each call is counted on the node of its caller, as the callee may be on another one
+        let calls = users
+            .iter()
+            .filter(|user| user.phase == Phase::InCall && user.caller)
+            .count();
+        self.connected_users.set(users.len() as i64);
+        self.active_calls.set(calls as i64);
+
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("can't encode metrics");
+        String::from_utf8(buffer).expect("metrics aren't UTF-8")
+    }
+}
+
+fn message_type(message: &PcMessage) -> &'static str {
+    match message {
+        PcMessage::Connect(_) => "connect",
+        PcMessage::ConnectResponse(_) => "connectresponse",
+        PcMessage::UserList(_) => "userlist",
+        PcMessage::Webrtc(_) => "webrtc",
+        PcMessage::Call(_) => "call",
+        PcMessage::CallReceived(_) => "callreceived",
+        PcMessage::CallHangup => "callhangup",
+        PcMessage::CallCancelled => "callcancelled",
+        PcMessage::CallResponse(_) => "callresponse",
+        PcMessage::Recording(_) => "recording",
+        PcMessage::SetPresence(_) => "setpresence",
+        PcMessage::UserJoined(_) => "userjoined",
+        PcMessage::UserLeft(_) => "userleft",
+        PcMessage::UserUpdated(_) => "userupdated",
+        PcMessage::CallStats(_) => "callstats",
+        PcMessage::IceServers(_) => "iceservers",
+        PcMessage::Announcement(_) => "announcement",
+        PcMessage::Kicked(_) => "kicked",
+        PcMessage::ProtocolError(_) => "protocolerror",
+    }
+}
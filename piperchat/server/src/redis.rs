@@ -0,0 +1,389 @@
+//! A bus over the publish/subscribe of a Redis server, or anything else speaking its protocol.
+//! It keeps a connection for publishing and one for the subscriptions, and reconnects when
+//! they're lost.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::bus::{Bus, QUEUE_SIZE};
+
+// How long to wait before connecting again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// How long a publication may wait to be sent. Later it's dropped, as it would arrive too late
+// to be of use.
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Publications waiting to be sent, more are dropped
+const PUBLICATION_QUEUE: usize = 1024;
+
+// Largest value taken from the server. The user lists of big nodes are the largest messages
+// on the bus, far below this, so a larger length is taken for a corrupted reply.
+const MAX_BULK_SIZE: usize = 16 * 1024 * 1024;
+
+// Longest line taken from the server. Lines only hold a type and a number, or a short status
+// or error.
+const MAX_LINE: u64 = 4096;
+
+type Subscriber = mpsc::Sender<Vec<u8>>;
+
+#[derive(Debug)]
+struct Publication {
+    channel: String,
+    payload: Vec<u8>,
+    // subscriptions that have to be in place before it's published
+    after: u64,
+    // when it's dropped if it isn't sent yet
+    deadline: Instant,
+}
+
+/// A bus over a Redis server shared by the instances
+#[derive(Debug)]
+pub struct RedisBus {
+    publications: mpsc::Sender<Publication>,
+    // only asked for once per channel, so there are a few at most
+    subscriptions: mpsc::UnboundedSender<(String, Subscriber)>,
+    // subscriptions asked for so far
+    requested: AtomicU64,
+}
+
+impl RedisBus {
+    /// Use the server at `address`, as `host:port`. Has to be called within a Tokio runtime.
+    pub fn new(address: String, password: Option<String>) -> Self {
+        let (publications, publications_rx) = mpsc::channel(PUBLICATION_QUEUE);
+        let (subscriptions, subscriptions_rx) = mpsc::unbounded_channel();
+        let (in_place, in_place_rx) = watch::channel(0);
+        tokio::spawn(subscribe(
+            address.clone(),
+            password.clone(),
+            subscriptions_rx,
+            in_place,
+        ));
+        tokio::spawn(publish(address, password, publications_rx, in_place_rx));
+
+        RedisBus {
+            publications,
+            subscriptions,
+            requested: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Bus for RedisBus {
+    fn publish(&self, channel: &str, payload: Vec<u8>) -> bool {
+        let publication = Publication {
+            channel: channel.to_string(),
+            payload,
+            after: self.requested.load(Ordering::SeqCst),
+            deadline: Instant::now() + PUBLISH_TIMEOUT,
+        };
+        self.publications.try_send(publication).is_ok()
+    }
+
+    fn subscribe(&self, channel: &str) -> mpsc::Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        self.requested.fetch_add(1, Ordering::SeqCst);
+        self.subscriptions.send((channel.to_string(), tx)).ok();
+        rx
+    }
+}
+
+// Publish what's sent to `publications`, each once the subscriptions made before it are in
+// place. Publications are dropped while the server can't be reached, and when they can't be
+// sent before their deadline.
+async fn publish(
+    address: String,
+    password: Option<String>,
+    mut publications: mpsc::Receiver<Publication>,
+    mut in_place: watch::Receiver<u64>,
+) {
+    let mut connection = None;
+    let mut retry = Instant::now();
+
+    while let Some(publication) = publications.recv().await {
+        // the subscriptions aren't confirmed while the server can't be reached
+        let subscribed = async {
+            while *in_place.borrow() < publication.after {
+                in_place.changed().await?;
+            }
+            Ok::<_, watch::error::RecvError>(())
+        };
+        match timeout_at(publication.deadline, subscribed).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return,
+            Err(_) => {
+                debug!("dropping a publication on {}", publication.channel);
+                continue;
+            }
+        }
+
+        if connection.is_none() && Instant::now() >= retry {
+            let opening = Connection::open(&address, password.as_deref());
+            match timeout_at(publication.deadline, opening).await {
+                Ok(Ok(opened)) => connection = Some(opened),
+                Ok(Err(err)) => {
+                    warn!("can't connect to {address} to publish: {err}");
+                    retry = Instant::now() + RECONNECT_DELAY;
+                }
+                Err(_) => {
+                    warn!("timed out connecting to {address} to publish");
+                    retry = Instant::now() + RECONNECT_DELAY;
+                }
+            }
+        }
+        let open = match connection.as_mut() {
+            Some(open) => open,
+            None => continue,
+        };
+
+        let command = [
+            &b"PUBLISH"[..],
+            publication.channel.as_bytes(),
+            &publication.payload,
+        ];
+        if let Err(err) = open.command(&command).await {
+            warn!("lost the connection to {address}: {err}");
+            connection = None;
+        }
+    }
+}
+
+// Subscribe to the channels sent to `subscriptions` and pass on what's published on them.
+// `in_place` counts the subscriptions that are.
+async fn subscribe(
+    address: String,
+    password: Option<String>,
+    mut subscriptions: mpsc::UnboundedReceiver<(String, Subscriber)>,
+    in_place: watch::Sender<u64>,
+) {
+    let mut channels: HashMap<String, Vec<Subscriber>> = HashMap::new();
+    let mut requested = 0;
+
+    loop {
+        let connection = match Connection::open(&address, password.as_deref()).await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("can't connect to {address} to subscribe: {err}");
+                sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        info!("subscribing on {address}");
+        let Connection { reader, mut writer } = connection;
+
+        // read in a task of its own, as reading can't be interrupted
+        let (values_tx, mut values) = mpsc::channel(QUEUE_SIZE);
+        let reading = tokio::spawn(async move {
+            let mut reader = reader;
+            loop {
+                let value = read_value(&mut reader).await;
+                let failed = value.is_err();
+                if values_tx.send(value).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        // confirmations to come, with the number of subscriptions in place once they're in
+        let mut pending = VecDeque::new();
+        let mut result = Ok(());
+        if !channels.is_empty() {
+            let mut command = vec![&b"SUBSCRIBE"[..]];
+            command.extend(channels.keys().map(|channel| channel.as_bytes()));
+            result = write_command(&mut writer, &command).await;
+            pending.extend(vec![None; channels.len() - 1]);
+            pending.push_back(Some(requested));
+        }
+
+        while result.is_ok() {
+            select! {
+                subscription = subscriptions.recv() => {
+                    let (channel, subscriber) = match subscription {
+                        Some(subscription) => subscription,
+                        None => {
+                            reading.abort();
+                            return;
+                        }
+                    };
+                    requested += 1;
+                    let command = [&b"SUBSCRIBE"[..], channel.as_bytes()];
+                    result = write_command(&mut writer, &command).await;
+                    pending.push_back(Some(requested));
+                    channels.entry(channel).or_default().push(subscriber);
+                }
+                value = values.recv() => {
+                    let items = match value {
+                        Some(Ok(Value::Array(items))) => items,
+                        Some(Ok(value)) => {
+                            warn!("unexpected reply from {address}: {value:?}");
+                            continue;
+                        }
+                        Some(Err(err)) => {
+                            result = Err(err);
+                            continue;
+                        }
+                        None => break,
+                    };
+                    match items.as_slice() {
+                        [Value::Bulk(kind), Value::Bulk(channel), Value::Bulk(payload)]
+                            if kind == b"message" =>
+                        {
+                            let channel = String::from_utf8_lossy(channel);
+                            if let Some(subscribers) = channels.get_mut(channel.as_ref()) {
+                                subscribers.retain(|subscriber| match subscriber.try_send(payload.clone()) {
+                                    Ok(()) => true,
+                                    Err(mpsc::error::TrySendError::Full(_)) => {
+                                        warn!("a subscriber is falling behind, dropping a message on {channel}");
+                                        true
+                                    }
+                                    Err(mpsc::error::TrySendError::Closed(_)) => false,
+                                });
+                            }
+                        }
+                        [Value::Bulk(kind), ..] if kind == b"subscribe" => {
+                            if let Some(Some(subscribed)) = pending.pop_front() {
+                                in_place.send_replace(subscribed);
+                            }
+                        }
+                        _ => warn!("unexpected message from {address}: {items:?}"),
+                    }
+                }
+            }
+        }
+
+        match result {
+            Ok(()) => warn!("lost the connection to {address}"),
+            Err(err) => warn!("lost the connection to {address}: {err}"),
+        }
+        reading.abort();
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+// A value of the Redis protocol, RESP 2. Arrays can't be nested, which nothing here needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Value>),
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    async fn open(address: &str, password: Option<&str>) -> io::Result<Self> {
+        let stream = TcpStream::connect(address).await?;
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        let mut connection = Connection {
+            reader: BufReader::new(reader),
+            writer,
+        };
+        if let Some(password) = password {
+            connection
+                .command(&[&b"AUTH"[..], password.as_bytes()])
+                .await?;
+        }
+
+        Ok(connection)
+    }
+
+    // send a command and read the reply, failing if it's an error
+    async fn command(&mut self, command: &[&[u8]]) -> io::Result<Value> {
+        write_command(&mut self.writer, command).await?;
+        match read_value(&mut self.reader).await? {
+            Value::Error(err) => Err(io::Error::other(err)),
+            value => Ok(value),
+        }
+    }
+}
+
+async fn write_command(writer: &mut OwnedWriteHalf, command: &[&[u8]]) -> io::Result<()> {
+    let mut buffer = format!("*{}\r\n", command.len()).into_bytes();
+    for argument in command {
+        buffer.extend(format!("${}\r\n", argument.len()).as_bytes());
+        buffer.extend(*argument);
+        buffer.extend(b"\r\n");
+    }
+    writer.write_all(&buffer).await
+}
+
+async fn read_value(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Value> {
+    let line = read_line(reader).await?;
+    if let Some(count) = line.strip_prefix('*') {
+        let count: i64 = count.parse().map_err(|_| invalid(&line))?;
+        let mut items = Vec::new();
+        for _ in 0..count {
+            let line = read_line(reader).await?;
+            if line.starts_with('*') {
+                return Err(invalid(&line));
+            }
+            items.push(read_scalar(reader, &line).await?);
+        }
+        return Ok(Value::Array(items));
+    }
+
+    read_scalar(reader, &line).await
+}
+
+// the value starting with `line`, which isn't an array
+async fn read_scalar(reader: &mut BufReader<OwnedReadHalf>, line: &str) -> io::Result<Value> {
+    let (kind, rest) = line.split_at(line.len().min(1));
+    match kind {
+        "+" => Ok(Value::Simple(rest.to_string())),
+        "-" => Ok(Value::Error(rest.to_string())),
+        ":" => rest.parse().map(Value::Integer).map_err(|_| invalid(line)),
+        "$" => {
+            let length: i64 = rest.parse().map_err(|_| invalid(line))?;
+            let length = match usize::try_from(length) {
+                Ok(length) if length > MAX_BULK_SIZE => return Err(invalid(line)),
+                Ok(length) => length,
+                Err(_) => return Ok(Value::Null),
+            };
+            let mut bulk = vec![0; length + 2];
+            reader.read_exact(&mut bulk).await?;
+            if !bulk.ends_with(b"\r\n") {
+                return Err(invalid(line));
+            }
+            bulk.truncate(length);
+            Ok(Value::Bulk(bulk))
+        }
+        _ => Err(invalid(line)),
+    }
+}
+
+async fn read_line(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<String> {
+    let mut line = String::new();
+    // a longer line is cut short and lacks the end of line
+    let mut limited = (&mut *reader).take(MAX_LINE);
+    if limited.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    match line.strip_suffix("\r\n") {
+        Some(line) => Ok(line.to_string()),
+        None => Err(invalid(&line)),
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid reply {line:?}"),
+    )
+}
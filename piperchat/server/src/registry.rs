@@ -0,0 +1,562 @@
+//! The registry of connected users. It's owned by a task of its own which the connections and
+//! the admin interfaces send their requests to, so no lock is shared by every connection.
+//!
+//! It also keeps track of the users on the other nodes of the cluster, passes on the commands
+//! for its users sent by the other nodes, and shares the bans with them.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use log::{info, warn};
+use tokio::select;
+use tokio::sync::{mpsc, oneshot};
+
+use piperchat_protocol::call::Phase;
+use piperchat_protocol::{Presence, UserInfo};
+
+use crate::cluster::{BusMessage, Cluster, Envelope, RemotePeer, HEARTBEAT, NODE_TIMEOUT};
+use crate::commands::{Ban, BanTarget};
+use crate::metrics::Rejection;
+use crate::{pc, Command, PcMessage, Peer, Queue};
+
+// Requests waiting for the registry, senders wait for room once it's full
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub(crate) name: String,
+    pub(crate) id: u32,
+    pub(crate) presence: Presence,
+    pub(crate) status: String,
+    pub(crate) address: IpAddr,
+    pub(crate) tx: Queue,
+    // mirrors the state of the connection's call, for the admin endpoint
+    pub(crate) phase: Phase,
+    /// Who the call is with: their node, none for this one, and their id
+    pub(crate) peer: Option<(Option<String>, u32)>,
+    /// Whether they placed the call
+    pub(crate) caller: bool,
+}
+
+impl User {
+    pub(crate) fn info(&self) -> UserInfo {
+        UserInfo {
+            id: self.id,
+            name: self.name.clone(),
+            presence: self.presence,
+            status: self.status.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Request {
+    Register {
+        user: User,
+        max_users: usize,
+        reply: oneshot::Sender<Result<(), (Rejection, String)>>,
+    },
+    Unregister(u32),
+    SetPresence {
+        id: u32,
+        presence: pc::PresenceMessage,
+    },
+    SetCall {
+        id: u32,
+        phase: Phase,
+        peer: Option<(Option<String>, u32)>,
+        caller: bool,
+    },
+    Lookup {
+        id: u32,
+        from: Queue,
+        reply: oneshot::Sender<Option<(Presence, Peer)>>,
+    },
+    Users(oneshot::Sender<Vec<User>>),
+    Kick {
+        name: String,
+        reason: String,
+        reply: oneshot::Sender<bool>,
+    },
+    Ban(Ban),
+    Unban {
+        target: BanTarget,
+        reply: oneshot::Sender<bool>,
+    },
+    Announce(PcMessage),
+}
+
+/// Handle to the registry, its task stops once every handle is dropped
+#[derive(Clone)]
+pub(crate) struct Registry(mpsc::Sender<Request>);
+
+impl Registry {
+    /// Start the registry's task, which has to be done within a Tokio runtime
+    pub(crate) fn spawn(cluster: Arc<Cluster>) -> Self {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run(rx, cluster));
+        Registry(tx)
+    }
+
+    async fn send(&self, request: Request) {
+        // the task only stops once there are no handles left
+        self.0.send(request).await.expect("registry stopped");
+    }
+
+    async fn query<T>(&self, request: impl FnOnce(oneshot::Sender<T>) -> Request) -> T {
+        let (reply, response) = oneshot::channel();
+        self.send(request(reply)).await;
+        response.await.expect("registry stopped")
+    }
+
+    /// Add `user`, unless they're banned, their name or id is taken or there are `max_users`
+    /// already. Once they're in, they're sent the list of users and the others are told.
+    pub(crate) async fn register(
+        &self,
+        user: User,
+        max_users: usize,
+    ) -> Result<(), (Rejection, String)> {
+        self.query(|reply| Request::Register {
+            user,
+            max_users,
+            reply,
+        })
+        .await
+    }
+
+    pub(crate) async fn unregister(&self, id: u32) {
+        self.send(Request::Unregister(id)).await;
+    }
+
+    pub(crate) async fn set_presence(&self, id: u32, presence: pc::PresenceMessage) {
+        self.send(Request::SetPresence { id, presence }).await;
+    }
+
+    /// Record the phase of the user's call, who it's with and whether the user placed it
+    pub(crate) async fn set_call(
+        &self,
+        id: u32,
+        phase: Phase,
+        peer: Option<(Option<String>, u32)>,
+        caller: bool,
+    ) {
+        self.send(Request::SetCall {
+            id,
+            phase,
+            peer,
+            caller,
+        })
+        .await;
+    }
+
+    /// The presence of the user with `id`, on any node, and how to reach them from the user
+    /// with the queue `from`. A user of this node comes first, then the one on the node with
+    /// the lowest name.
+    pub(crate) async fn lookup(&self, id: u32, from: Queue) -> Option<(Presence, Peer)> {
+        self.query(|reply| Request::Lookup { id, from, reply })
+            .await
+    }
+
+    /// The users connected to this node
+    pub(crate) async fn users(&self) -> Vec<User> {
+        self.query(Request::Users).await
+    }
+
+    /// Disconnect the user called `name`, on any node, false if there's none
+    pub(crate) async fn kick(&self, name: String, reason: String) -> bool {
+        self.query(|reply| Request::Kick {
+            name,
+            reason,
+            reply,
+        })
+        .await
+    }
+
+    /// Disconnect the users `ban` applies to and keep them out of every node until it
+    /// expires. It replaces an earlier ban of the same target.
+    pub(crate) async fn ban(&self, ban: Ban) {
+        self.send(Request::Ban(ban)).await;
+    }
+
+    /// Lift the ban of `target` on every node, false if there's none
+    pub(crate) async fn unban(&self, target: BanTarget) -> bool {
+        self.query(|reply| Request::Unban { target, reply }).await
+    }
+
+    /// Send `message` to every user of this node
+    pub(crate) async fn announce(&self, message: PcMessage) {
+        self.send(Request::Announce(message)).await;
+    }
+}
+
+async fn run(mut requests: mpsc::Receiver<Request>, cluster: Arc<Cluster>) {
+    let (mut directory, mut inbox) = cluster.subscribe();
+    cluster.broadcast(BusMessage::Hello);
+    let mut heartbeat = tokio::time::interval(HEARTBEAT);
+    let mut state = State::new(cluster);
+
+    loop {
+        select! {
+            // what the other nodes say is taken in before the requests that might depend on it
+            biased;
+            _ = heartbeat.tick() => state.heartbeat(),
+            Some(payload) = directory.recv() => state.receive(&payload),
+            Some(payload) = inbox.recv() => state.receive(&payload),
+            request = requests.recv() => match request {
+                Some(request) => state.handle(request),
+                None => break,
+            },
+        }
+    }
+}
+
+// A user connected to another node
+struct RemoteUser {
+    node: String,
+    info: UserInfo,
+}
+
+struct State {
+    users: HashMap<u32, User>,
+    bans: Vec<Ban>,
+    cluster: Arc<Cluster>,
+    // by node and id, as two nodes can let users with the same id in at the same time
+    remote: HashMap<(String, u32), RemoteUser>,
+    // the other nodes, with when they were last heard from
+    nodes: HashMap<String, Instant>,
+}
+
+impl State {
+    fn new(cluster: Arc<Cluster>) -> Self {
+        State {
+            users: HashMap::new(),
+            bans: Vec::new(),
+            cluster,
+            remote: HashMap::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    // a reply can't be sent when the requester is gone, which is fine
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Register {
+                user,
+                max_users,
+                reply,
+            } => {
+                reply.send(self.register(user, max_users)).ok();
+            }
+            Request::Unregister(id) => {
+                if self.users.remove(&id).is_some() {
+                    self.broadcast(id, PcMessage::UserLeft(id));
+                    self.cluster.broadcast(BusMessage::Left { id });
+                }
+            }
+            Request::SetPresence { id, presence } => {
+                if let Some(user) = self.users.get_mut(&id) {
+                    user.presence = presence.presence;
+                    user.status = presence.status;
+                    let user = user.info();
+                    self.broadcast(id, PcMessage::UserUpdated(user.clone()));
+                    self.cluster.broadcast(BusMessage::Updated { user });
+                }
+            }
+            Request::SetCall {
+                id,
+                phase,
+                peer,
+                caller,
+            } => {
+                if let Some(user) = self.users.get_mut(&id) {
+                    user.phase = phase;
+                    user.peer = peer;
+                    user.caller = caller;
+                }
+            }
+            Request::Lookup { id, from, reply } => {
+                let remote = self
+                    .remote
+                    .values()
+                    .filter(|user| user.info.id == id)
+                    .min_by(|a, b| a.node.cmp(&b.node));
+                let peer = match (self.users.get(&id), remote) {
+                    (Some(user), _) => Some((user.presence, Peer::Local(user.tx.clone()))),
+                    (None, Some(user)) => {
+                        let peer = RemotePeer {
+                            cluster: self.cluster.clone(),
+                            node: user.node.clone(),
+                            id,
+                            user: from,
+                        };
+                        Some((user.info.presence, Peer::Remote(peer)))
+                    }
+                    (None, None) => None,
+                };
+                reply.send(peer).ok();
+            }
+            Request::Users(reply) => {
+                reply.send(self.users.values().cloned().collect()).ok();
+            }
+            Request::Kick {
+                name,
+                reason,
+                reply,
+            } => {
+                let remote = self.remote.values().find(|user| user.info.name == name);
+                let kicked = match (self.kick(&name, &reason), remote) {
+                    (true, _) => true,
+                    (false, Some(user)) => {
+                        self.cluster
+                            .send(&user.node, BusMessage::Kick { name, reason });
+                        true
+                    }
+                    (false, None) => false,
+                };
+                reply.send(kicked).ok();
+            }
+            Request::Ban(ban) => {
+                self.cluster.broadcast(banned(&ban));
+                self.add_ban(ban);
+            }
+            Request::Unban { target, reply } => {
+                self.cluster.broadcast(BusMessage::Unbanned {
+                    target: target.clone(),
+                });
+                reply.send(self.unban(&target)).ok();
+            }
+            Request::Announce(message) => {
+                for user in self.users.values() {
+                    user.tx.deliver(Command::SendMessage(message.clone()));
+                }
+            }
+        }
+    }
+
+    fn register(&mut self, user: User, max_users: usize) -> Result<(), (Rejection, String)> {
+        if let Some(ban) = self.ban(&user.name, user.address) {
+            let reason = match ban.reason.as_str() {
+                "" => "You are banned from this server".to_string(),
+                reason => format!("You are banned from this server: {reason}"),
+            };
+            return Err((Rejection::Banned, reason));
+        }
+        let local = self.users.values().map(|other| &other.name);
+        let remote = self.remote.values().map(|other| &other.info.name);
+        if local.chain(remote).any(|name| *name == user.name) {
+            let reason = "User with this name already exist. Please pick a different name";
+            return Err((Rejection::NameTaken, reason.to_string()));
+        }
+        let mut remote = self.remote.values();
+        if self.users.contains_key(&user.id) || remote.any(|other| other.info.id == user.id) {
+            let reason = "User with this id already exists. Please try again";
+            return Err((Rejection::IdTaken, reason.to_string()));
+        }
+        if self.users.len() >= max_users {
+            let reason = "The server is full. Please try again later";
+            return Err((Rejection::Full, reason.to_string()));
+        }
+
+        self.send_user_list(&user);
+        self.broadcast(user.id, PcMessage::UserJoined(user.info()));
+        self.cluster
+            .broadcast(BusMessage::Joined { user: user.info() });
+        self.users.insert(user.id, user);
+        Ok(())
+    }
+
+    // a message from the bus
+    fn receive(&mut self, payload: &[u8]) {
+        let Envelope { node, message } = match serde_json::from_slice(payload) {
+            Ok(envelope) => envelope,
+            Err(err) => {
+                warn!("invalid message on the bus: {err}");
+                return;
+            }
+        };
+        if node == self.cluster.node {
+            return;
+        }
+
+        if self.nodes.insert(node.clone(), Instant::now()).is_none() {
+            info!("{node} joined the cluster");
+            // its users are only announced as they change otherwise
+            if !matches!(message, BusMessage::Hello | BusMessage::Users { .. }) {
+                self.cluster.send(&node, BusMessage::Hello);
+            }
+        }
+
+        match message {
+            BusMessage::Hello => {
+                let users = self.users.values().map(User::info).collect();
+                self.cluster.send(&node, BusMessage::Users { users });
+                let now = Instant::now();
+                for ban in self.bans.iter().filter(|ban| ban.until > now) {
+                    self.cluster.send(&node, banned(ban));
+                }
+            }
+            BusMessage::Users { users } => {
+                let ids: Vec<u32> = users.iter().map(|user| user.id).collect();
+                self.remove_remote(|user| user.node == node && !ids.contains(&user.info.id));
+                for user in users {
+                    self.add_remote(&node, user);
+                }
+            }
+            BusMessage::Joined { user } | BusMessage::Updated { user } => {
+                self.add_remote(&node, user);
+            }
+            BusMessage::Left { id } => {
+                self.remove_remote(|user| user.node == node && user.info.id == id);
+            }
+            BusMessage::Deliver { to, command } => match self.users.get(&to) {
+                Some(user) => {
+                    let command = command.into_command(&self.cluster, &node, &user.tx);
+                    user.tx.deliver(command);
+                }
+                None => info!("{node} sent a command for {to}, who isn't here"),
+            },
+            BusMessage::Kick { name, reason } => {
+                if !self.kick(&name, &reason) {
+                    info!("{node} kicked {name}, who isn't here");
+                }
+            }
+            BusMessage::Banned {
+                target,
+                duration,
+                reason,
+            } => match Instant::now().checked_add(duration) {
+                Some(until) => self.add_ban(Ban {
+                    target,
+                    until,
+                    reason,
+                }),
+                None => warn!("{node} banned {target} for too long: {duration:?}"),
+            },
+            BusMessage::Unbanned { target } => {
+                self.unban(&target);
+            }
+        }
+    }
+
+    // tell the others this node is still there along with all its users, making up for what
+    // they missed, and forget the nodes that aren't
+    fn heartbeat(&mut self) {
+        let users = self.users.values().map(User::info).collect();
+        self.cluster.broadcast(BusMessage::Users { users });
+
+        let now = Instant::now();
+        let gone: Vec<String> = self
+            .nodes
+            .iter()
+            .filter(|(_, heard)| now.duration_since(**heard) > NODE_TIMEOUT)
+            .map(|(node, _)| node.clone())
+            .collect();
+        for node in gone {
+            warn!("{node} left the cluster");
+            self.nodes.remove(&node);
+            self.remove_remote(|user| user.node == node);
+        }
+    }
+
+    // add a user on `node` or update them, and let the users here know if anything changed
+    fn add_remote(&mut self, node: &str, user: UserInfo) {
+        let id = user.id;
+        let remote = RemoteUser {
+            node: node.to_string(),
+            info: user.clone(),
+        };
+        let message = match self.remote.insert((node.to_string(), id), remote) {
+            Some(previous) if previous.info == user => return,
+            Some(_) => PcMessage::UserUpdated(user),
+            None => PcMessage::UserJoined(user),
+        };
+        self.broadcast(id, message);
+    }
+
+    // remove the users on other nodes that match, ending the calls with them
+    fn remove_remote(&mut self, matches: impl Fn(&RemoteUser) -> bool) {
+        let keys: Vec<(String, u32)> = self
+            .remote
+            .iter()
+            .filter(|(_, user)| matches(user))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            let (node, id) = key.clone();
+            self.remote.remove(&key);
+            self.broadcast(id, PcMessage::UserLeft(id));
+            let peer = Some((Some(node), id));
+            for user in self.users.values().filter(|user| user.peer == peer) {
+                user.tx.deliver(Command::PeerGone);
+            }
+        }
+    }
+
+    // disconnect the user of this node called `name`, false if there's none
+    fn kick(&self, name: &str, reason: &str) -> bool {
+        let user = self.users.values().find(|user| user.name == name);
+        if let Some(user) = user {
+            user.tx.deliver(Command::Kick(reason.to_string()));
+        }
+        user.is_some()
+    }
+
+    // disconnect the users of this node `ban` applies to and keep them out
+    fn add_ban(&mut self, ban: Ban) {
+        for user in self.users.values() {
+            if ban.target.matches(&user.name, user.address) {
+                user.tx.deliver(Command::Kick(ban.reason.clone()));
+            }
+        }
+        self.bans.retain(|banned| banned.target != ban.target);
+        self.bans.push(ban);
+    }
+
+    // lift the ban of `target`, false if there's none
+    fn unban(&mut self, target: &BanTarget) -> bool {
+        let bans = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        self.bans.len() < bans
+    }
+
+    // the ban keeping `name` at `address` out, if any; expired bans are dropped
+    fn ban(&mut self, name: &str, address: IpAddr) -> Option<&Ban> {
+        let now = Instant::now();
+        self.bans.retain(|ban| ban.until > now);
+        self.bans
+            .iter()
+            .find(|ban| ban.target.matches(name, address))
+    }
+
+    // send the list of all the other users to a newly connected user
+    fn send_user_list(&self, user: &User) {
+        let local = self
+            .users
+            .values()
+            .filter(|u| u.id != user.id)
+            .map(User::info);
+        let remote = self.remote.values().map(|u| u.info.clone());
+        let userlist_message = PcMessage::UserList(pc::UserList {
+            users: local.chain(remote).collect(),
+        });
+        user.tx.deliver(Command::SendMessage(userlist_message));
+    }
+
+    // send a message to every user of this node except the one with id `except`
+    fn broadcast(&self, except: u32, message: PcMessage) {
+        for user in self.users.values().filter(|u| u.id != except) {
+            user.tx.deliver(Command::SendMessage(message.clone()));
+        }
+    }
+}
+
+// `ban` for the other nodes, which don't share our clock
+fn banned(ban: &Ban) -> BusMessage {
+    BusMessage::Banned {
+        target: ban.target.clone(),
+        duration: ban.until.saturating_duration_since(Instant::now()),
+        reason: ban.reason.clone(),
+    }
+}
@@ -0,0 +1,46 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use color_eyre::eyre::{eyre, WrapErr};
+use rustls_pemfile::Item;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::TlsConfig;
+
+/// Read the certificate chain and key named by `config`
+pub(crate) fn acceptor(config: &TlsConfig) -> color_eyre::Result<TlsAcceptor> {
+    let certificates = rustls_pemfile::certs(&mut reader(&config.certificate)?)
+        .wrap_err_with(|| format!("invalid certificate {}", config.certificate.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = private_key(&config.key)
+        .wrap_err_with(|| format!("invalid key {}", config.key.display()))?;
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
+
+fn reader(path: &Path) -> color_eyre::Result<BufReader<File>> {
+    let file = File::open(path).wrap_err_with(|| format!("can't open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+// the first key in the file, in any of the formats rustls takes
+fn private_key(path: &Path) -> color_eyre::Result<PrivateKey> {
+    let mut reader = reader(path)?;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        if let Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) = item {
+            return Ok(PrivateKey(key));
+        }
+    }
+
+    Err(eyre!("no private key found"))
+}
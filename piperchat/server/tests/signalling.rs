@@ -0,0 +1,1258 @@
+//! Runs the server on an ephemeral port and drives it with scripted protocol-level clients.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use piperchat_protocol::{
+    CallMessage, CallReceivedMessage, CallResponseMessage, ConnectMessage, ConnectResponse,
+    IceServersMessage, Message, Presence, PresenceMessage, WebrtcMsg,
+};
+use piperchat_server::bus::{Bus, MemoryBus};
+use piperchat_server::config::{AdminConfig, AuthConfig, ClusterConfig, IceServers, Limits};
+use piperchat_server::redis::RedisBus;
+use piperchat_server::{Config, Server};
+
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+async fn start_server(config: Config) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(piperchat_server::serve(listener, config));
+
+    address
+}
+
+// The body of a GET request to the admin endpoint
+async fn get(address: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    let request = format!("GET {path} HTTP/1.0\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.0 200"), "{path} failed: {head}");
+    body.to_string()
+}
+
+// A session on the admin socket
+struct AdminSession {
+    lines: tokio::io::Lines<BufReader<tokio::net::unix::OwnedReadHalf>>,
+    writer: tokio::net::unix::OwnedWriteHalf,
+}
+
+impl AdminSession {
+    async fn connect(path: &std::path::Path) -> Self {
+        let (reader, writer) = UnixStream::connect(path).await.unwrap().into_split();
+        AdminSession {
+            lines: BufReader::new(reader).lines(),
+            writer,
+        }
+    }
+
+    /// Send a line, returning the reply
+    async fn send(&mut self, line: &str) -> String {
+        self.writer
+            .write_all(format!("{line}\n").as_bytes())
+            .await
+            .unwrap();
+        self.lines.next_line().await.unwrap().unwrap()
+    }
+}
+
+// A server taking admin commands with the token `secret`
+async fn start_server_with_admin_socket() -> (SocketAddr, AdminSession, Server) {
+    start_node_with_admin_socket("a", Arc::new(MemoryBus::default())).await
+}
+
+// A node of a cluster taking admin commands with the token `secret`
+async fn start_node_with_admin_socket(
+    node: &str,
+    bus: Arc<dyn Bus>,
+) -> (SocketAddr, AdminSession, Server) {
+    let path = std::env::temp_dir().join(format!(
+        "piperchat-admin-{}-{:?}.sock",
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    let config = Config {
+        admin: AdminConfig {
+            token: Some("secret".to_string()),
+            ..AdminConfig::default()
+        },
+        cluster: ClusterConfig {
+            node: Some(node.to_string()),
+            ..ClusterConfig::default()
+        },
+        ..Config::default()
+    };
+    let server = Server::with_bus(config, bus).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    server.spawn(listener);
+    std::fs::remove_file(&path).ok();
+    server.spawn_commands(UnixListener::bind(&path).unwrap());
+
+    let mut admin = AdminSession::connect(&path).await;
+    assert_eq!(admin.send("token secret").await, "ok");
+    std::fs::remove_file(&path).unwrap();
+
+    (address, admin, server)
+}
+
+// A node of a cluster, reaching the others over `bus`
+async fn start_node(node: &str, bus: Arc<dyn Bus>) -> SocketAddr {
+    let config = Config {
+        cluster: ClusterConfig {
+            node: Some(node.to_string()),
+            ..ClusterConfig::default()
+        },
+        ..Config::default()
+    };
+    let server = Server::with_bus(config, bus).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    server.spawn(listener);
+
+    address
+}
+
+// A bus losing the messages containing `lost`, as if it was falling behind
+#[derive(Debug, Default)]
+struct LossyBus {
+    bus: MemoryBus,
+    lost: &'static str,
+}
+
+impl Bus for LossyBus {
+    fn publish(&self, channel: &str, payload: Vec<u8>) -> bool {
+        if String::from_utf8_lossy(&payload).contains(self.lost) {
+            return false;
+        }
+        self.bus.publish(channel, payload)
+    }
+
+    fn subscribe(&self, channel: &str) -> mpsc::Receiver<Vec<u8>> {
+        self.bus.subscribe(channel)
+    }
+}
+
+type Subscribers = Arc<Mutex<HashMap<Vec<u8>, Vec<mpsc::UnboundedSender<Vec<u8>>>>>>;
+
+// A stand-in for a Redis server, with just enough of publish/subscribe for the bus
+async fn start_pubsub_server() -> (SocketAddr, Subscribers) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+
+    (address, serve_pubsub(listener))
+}
+
+fn serve_pubsub(listener: TcpListener) -> Subscribers {
+    let subscribers = Subscribers::default();
+    let shared = subscribers.clone();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(pubsub_connection(stream, shared.clone()));
+        }
+    });
+
+    subscribers
+}
+
+async fn pubsub_connection(stream: TcpStream, subscribers: Subscribers) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    // replies and the messages published to the connection's channels
+    let (tx, mut rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    tokio::spawn(async move {
+        while let Some(reply) = rx.recv().await {
+            if writer.write_all(&reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let bulk = |value: &[u8]| [format!("${}\r\n", value.len()).as_bytes(), value, b"\r\n"].concat();
+    let mut subscribed = 0;
+    loop {
+        // every command is an array of bulk strings
+        let mut line = String::new();
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let count: usize = line.trim_end()[1..].parse().unwrap();
+        let mut command = Vec::new();
+        for _ in 0..count {
+            line.clear();
+            reader.read_line(&mut line).await.unwrap();
+            let length: usize = line.trim_end()[1..].parse().unwrap();
+            let mut argument = vec![0; length + 2];
+            reader.read_exact(&mut argument).await.unwrap();
+            argument.truncate(length);
+            command.push(argument);
+        }
+
+        match command[0].as_slice() {
+            b"SUBSCRIBE" => {
+                for channel in &command[1..] {
+                    let mut subscribers = subscribers.lock().unwrap();
+                    let channel_subscribers = subscribers.entry(channel.clone()).or_default();
+                    if !channel_subscribers
+                        .iter()
+                        .any(|other| other.same_channel(&tx))
+                    {
+                        channel_subscribers.push(tx.clone());
+                        subscribed += 1;
+                    }
+                    let reply = [
+                        b"*3\r\n".to_vec(),
+                        bulk(b"subscribe"),
+                        bulk(channel),
+                        format!(":{subscribed}\r\n").into_bytes(),
+                    ];
+                    tx.send(reply.concat()).unwrap();
+                }
+            }
+            b"PUBLISH" => {
+                let message = [
+                    b"*3\r\n".to_vec(),
+                    bulk(b"message"),
+                    bulk(&command[1]),
+                    bulk(&command[2]),
+                ]
+                .concat();
+                let mut subscribers = subscribers.lock().unwrap();
+                let receivers = subscribers.entry(command[1].clone()).or_default();
+                receivers.retain(|receiver| receiver.send(message.clone()).is_ok());
+                tx.send(format!(":{}\r\n", receivers.len()).into_bytes())
+                    .unwrap();
+            }
+            b"AUTH" => tx.send(b"+OK\r\n".to_vec()).unwrap(),
+            _ => tx.send(b"-ERR unknown command\r\n".to_vec()).unwrap(),
+        }
+    }
+}
+
+struct TestClient {
+    id: u32,
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl TestClient {
+    /// Connect and register, returning the server's response to the registration
+    async fn register(address: SocketAddr, name: &str, id: u32) -> (Self, ConnectResponse) {
+        Self::register_at(&format!("ws://{address}"), name, id).await
+    }
+
+    async fn register_at(url: &str, name: &str, id: u32) -> (Self, ConnectResponse) {
+        let (ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let mut client = TestClient { id, ws };
+        client
+            .send(Message::Connect(ConnectMessage {
+                name: name.to_string(),
+                id,
+            }))
+            .await;
+
+        match client.recv().await {
+            Message::ConnectResponse(response) => (client, response),
+            message => panic!("expected a connect response, got {message:?}"),
+        }
+    }
+
+    /// Connect, expecting to be accepted and to receive the list of users
+    async fn connect(address: SocketAddr, name: &str, id: u32) -> (Self, Vec<String>) {
+        let (mut client, response) = Self::register(address, name, id).await;
+        assert!(matches!(response, ConnectResponse::Accept));
+
+        match client.recv().await {
+            Message::UserList(list) => {
+                let names = list.users.into_iter().map(|user| user.name).collect();
+                (client, names)
+            }
+            message => panic!("expected the user list, got {message:?}"),
+        }
+    }
+
+    async fn send(&mut self, message: Message) {
+        let text = serde_json::to_string(&message).unwrap();
+        self.ws.send(WsMessage::Text(text)).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Message {
+        loop {
+            let message = timeout(RECEIVE_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for a message")
+                .expect("connection closed")
+                .unwrap();
+            if let WsMessage::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    async fn call(&mut self, peer: &TestClient) {
+        self.send(Message::Call(CallMessage { peer: peer.id }))
+            .await;
+    }
+
+    async fn answer(&mut self, response: CallResponseMessage) {
+        self.send(Message::CallResponse(response)).await;
+    }
+
+    /// Expect a protocol error, skipping other messages, and return its reason
+    async fn expect_protocol_error(&mut self) -> String {
+        loop {
+            if let Message::ProtocolError(error) = self.recv().await {
+                return error.reason;
+            }
+        }
+    }
+
+    /// Expect the server to close the connection, returning the close code
+    async fn expect_close(&mut self) -> CloseCode {
+        loop {
+            let message = timeout(RECEIVE_TIMEOUT, self.ws.next())
+                .await
+                .expect("timed out waiting for the close frame");
+            match message {
+                Some(Ok(WsMessage::Close(Some(frame)))) => return frame.code,
+                Some(Ok(_)) => (),
+                message => panic!("expected a close frame, got {message:?}"),
+            }
+        }
+    }
+
+    async fn close(mut self) {
+        self.ws.close(None).await.unwrap();
+    }
+}
+
+// A server with `limits` changed from the defaults
+async fn start_server_with_limits(limits: Limits) -> SocketAddr {
+    start_server(Config {
+        limits,
+        ..Config::default()
+    })
+    .await
+}
+
+// Two connected users, with the join notifications already consumed
+async fn alice_and_bob(address: SocketAddr) -> (TestClient, TestClient) {
+    let (mut alice, _) = TestClient::connect(address, "alice", 1).await;
+    let (bob, _) = TestClient::connect(address, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(user) if user.name == "bob"));
+
+    (alice, bob)
+}
+
+// Alice calls Bob and Bob accepts
+async fn call_in_progress(address: SocketAddr) -> (TestClient, TestClient) {
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    alice.call(&bob).await;
+    assert!(matches!(
+        bob.recv().await,
+        Message::CallReceived(CallReceivedMessage { name }) if name == "alice"
+    ));
+    bob.answer(CallResponseMessage::Accept).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Accept)
+    ));
+
+    (alice, bob)
+}
+
+#[tokio::test]
+async fn connect_announces_users() {
+    let address = start_server(Config::default()).await;
+
+    let (mut alice, users) = TestClient::connect(address, "alice", 1).await;
+    assert!(users.is_empty());
+
+    let (_bob, users) = TestClient::connect(address, "bob", 2).await;
+    assert_eq!(users, vec!["alice".to_string()]);
+    assert!(
+        matches!(alice.recv().await, Message::UserJoined(user) if user.name == "bob" && user.id == 2)
+    );
+}
+
+#[tokio::test]
+async fn duplicate_name_is_rejected() {
+    let address = start_server(Config::default()).await;
+
+    let (_alice, _) = TestClient::connect(address, "alice", 1).await;
+    let (_impostor, response) = TestClient::register(address, "alice", 2).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+}
+
+#[tokio::test]
+async fn duplicate_id_is_rejected() {
+    let address = start_server(Config::default()).await;
+
+    let (_alice, _) = TestClient::connect(address, "alice", 7).await;
+    let (bob, response) = TestClient::register(address, "bob", 7).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+
+    // bob going away leaves alice registered
+    drop(bob);
+    let (_carol, users) = TestClient::connect(address, "carol", 8).await;
+    assert_eq!(users, vec!["alice"]);
+}
+
+#[tokio::test]
+async fn calling_someone_not_connected_is_rejected() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, _) = TestClient::connect(address, "alice", 1).await;
+
+    alice.send(Message::Call(CallMessage { peer: 4242 })).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Reject)
+    ));
+
+    // once alice leaves, her name is free again
+    alice.close().await;
+    timeout(RECEIVE_TIMEOUT, async {
+        loop {
+            let (_, response) = TestClient::register(address, "alice", 2).await;
+            if matches!(response, ConnectResponse::Accept) {
+                break;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("alice stayed registered");
+}
+
+#[tokio::test]
+async fn call_is_accepted_and_hung_up() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, mut bob) = call_in_progress(address).await;
+
+    // webrtc negotiation is relayed to the peer
+    alice
+        .send(Message::Webrtc(WebrtcMsg::Sdp {
+            type_: "offer".to_string(),
+            sdp: "v=0".to_string(),
+        }))
+        .await;
+    assert!(matches!(
+        bob.recv().await,
+        Message::Webrtc(WebrtcMsg::Sdp { type_, .. }) if type_ == "offer"
+    ));
+
+    alice.send(Message::CallHangup).await;
+    assert!(matches!(bob.recv().await, Message::CallHangup));
+
+    // both are free to call again
+    bob.call(&alice).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallReceived(CallReceivedMessage { name }) if name == "bob"
+    ));
+}
+
+#[tokio::test]
+async fn call_is_rejected() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    bob.answer(CallResponseMessage::Reject).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Reject)
+    ));
+}
+
+#[tokio::test]
+async fn callee_cant_send_server_responses() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    bob.answer(CallResponseMessage::NoAnswer).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Reject)
+    ));
+}
+
+#[tokio::test]
+async fn caller_cancels_ringing_call() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    alice.send(Message::CallHangup).await;
+    assert!(matches!(bob.recv().await, Message::CallHangup));
+}
+
+#[tokio::test]
+async fn unanswered_call_times_out() {
+    let mut config = Config::default();
+    config.limits.ring_timeout = Duration::from_millis(200);
+    let address = start_server(config).await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::NoAnswer)
+    ));
+    assert!(matches!(bob.recv().await, Message::CallCancelled));
+}
+
+#[tokio::test]
+async fn disconnect_during_call_hangs_up() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, bob) = call_in_progress(address).await;
+
+    bob.close().await;
+    assert!(matches!(alice.recv().await, Message::CallHangup));
+    assert!(matches!(alice.recv().await, Message::UserLeft(2)));
+}
+
+#[tokio::test]
+async fn full_server_rejects_users() {
+    let mut config = Config::default();
+    config.limits.max_users = 1;
+    let address = start_server(config).await;
+
+    let (_alice, _) = TestClient::connect(address, "alice", 1).await;
+    let (_bob, response) = TestClient::register(address, "bob", 2).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+}
+
+#[tokio::test]
+async fn ice_servers_are_sent_on_connect() {
+    let address = start_server(Config {
+        ice: IceServers {
+            stun: Some("stun://stun.example.com:3478".to_string()),
+            turn: None,
+        },
+        ..Config::default()
+    })
+    .await;
+
+    let (mut alice, response) = TestClient::register(address, "alice", 1).await;
+    assert!(matches!(response, ConnectResponse::Accept));
+    assert!(matches!(
+        alice.recv().await,
+        Message::IceServers(IceServersMessage { stun: Some(stun), turn: None })
+            if stun == "stun://stun.example.com:3478"
+    ));
+    assert!(matches!(alice.recv().await, Message::UserList(_)));
+}
+
+#[tokio::test]
+async fn tokens_are_checked() {
+    // sha256 of "secret"
+    let file = std::env::temp_dir().join(format!("piperchat-tokens-{}", std::process::id()));
+    std::fs::write(
+        &file,
+        "# test users\nalice:2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b\n",
+    )
+    .unwrap();
+    let address = start_server(Config {
+        auth: AuthConfig::Tokens { file: file.clone() },
+        ..Config::default()
+    })
+    .await;
+
+    let (_, response) = TestClient::register(address, "alice", 1).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+    let url = format!("ws://{address}/?token=wrong");
+    let (_, response) = TestClient::register_at(&url, "alice", 1).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+    let url = format!("ws://{address}/?token=secret");
+    let (_, response) = TestClient::register_at(&url, "bob", 2).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+    let (_alice, response) = TestClient::register_at(&url, "alice", 1).await;
+    assert!(matches!(response, ConnectResponse::Accept));
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[tokio::test]
+async fn tokens_are_decoded() {
+    // sha256 of "a+b/c=d&e"
+    let file =
+        std::env::temp_dir().join(format!("piperchat-encoded-tokens-{}", std::process::id()));
+    std::fs::write(
+        &file,
+        "alice:a85d72f9b0399447b414d1f5eaec5f1928552d08ce75b3d73beb52b61551e7ed\n",
+    )
+    .unwrap();
+    let address = start_server(Config {
+        auth: AuthConfig::Tokens { file: file.clone() },
+        ..Config::default()
+    })
+    .await;
+
+    let url = format!("ws://{address}/?token=a%2Bb%2Fc%3Dd%26e");
+    let (_alice, response) = TestClient::register_at(&url, "alice", 1).await;
+    assert!(matches!(response, ConnectResponse::Accept));
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[tokio::test]
+async fn reload_keeps_users_connected() {
+    let mut config = Config::default();
+    config.limits.max_users = 1;
+    let server = Server::new(config.clone()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    server.spawn(listener);
+
+    let (mut alice, _) = TestClient::connect(address, "alice", 1).await;
+    let (_, response) = TestClient::register(address, "bob", 2).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+
+    config.limits.max_users = 2;
+    server.reload(config).unwrap();
+    let (_bob, _) = TestClient::connect(address, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(user) if user.name == "bob"));
+}
+
+#[tokio::test]
+async fn admin_endpoint_reports_users_and_calls() {
+    let server = Server::new(Config::default()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    server.spawn(listener);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let admin = listener.local_addr().unwrap();
+    server.spawn_admin(listener);
+
+    let (_alice, _bob) = call_in_progress(address).await;
+    let (_alice, response) = TestClient::register(address, "alice", 3).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+
+    let users: serde_json::Value = serde_json::from_str(&get(admin, "/users").await).unwrap();
+    assert_eq!(users[0]["name"], "alice");
+    assert_eq!(users[0]["call"], "incall");
+    assert_eq!(users[0]["peer"], 2);
+    assert_eq!(users[1]["peer"], 1);
+
+    let metrics = get(admin, "/metrics").await;
+    for line in [
+        "piperchat_connected_users 2",
+        "piperchat_active_calls 1",
+        "piperchat_call_setup_seconds_count 1",
+        "piperchat_messages_total{direction=\"received\",type=\"call\"} 1",
+        "piperchat_rejected_connects_total{reason=\"name_taken\"} 1",
+    ] {
+        assert!(
+            metrics.lines().any(|l| l == line),
+            "{line} missing:\n{metrics}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn admin_socket_requires_the_token() {
+    let (_, _admin, server) = start_server_with_admin_socket().await;
+    let path = std::env::temp_dir().join(format!("piperchat-admin-{}.sock", std::process::id()));
+    std::fs::remove_file(&path).ok();
+    server.spawn_commands(UnixListener::bind(&path).unwrap());
+
+    let mut admin = AdminSession::connect(&path).await;
+    assert_eq!(admin.send("token guess").await, "error: wrong token");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn admin_kicks_and_announces() {
+    let (address, mut admin, _server) = start_server_with_admin_socket().await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    assert_eq!(admin.send("announce Maintenance at noon").await, "ok");
+    for client in [&mut alice, &mut bob] {
+        assert!(matches!(
+            client.recv().await,
+            Message::Announcement(announcement) if announcement.text == "Maintenance at noon"
+        ));
+    }
+
+    assert_eq!(admin.send("kick bob too loud").await, "ok");
+    assert!(matches!(bob.recv().await, Message::Kicked(kicked) if kicked.reason == "too loud"));
+    assert!(matches!(alice.recv().await, Message::UserLeft(2)));
+
+    assert!(admin.send("kick bob").await.starts_with("error:"));
+    assert!(admin.send("ban bob forever").await.starts_with("error:"));
+    assert!(admin.send("reboot").await.starts_with("error:"));
+}
+
+#[tokio::test]
+async fn banned_users_are_kept_out() {
+    let (address, mut admin, _server) = start_server_with_admin_socket().await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    assert_eq!(admin.send("ban bob 1h spam").await, "ok");
+    assert!(matches!(bob.recv().await, Message::Kicked(kicked) if kicked.reason == "spam"));
+    assert!(matches!(alice.recv().await, Message::UserLeft(2)));
+    let (_, response) = TestClient::register(address, "bob", 3).await;
+    assert!(matches!(response, ConnectResponse::Reject(reason) if reason.contains("spam")));
+
+    // everyone connects from the same address
+    assert_eq!(admin.send("ban 127.0.0.1 1m").await, "ok");
+    assert!(matches!(alice.recv().await, Message::Kicked(_)));
+    let (_, response) = TestClient::register(address, "carol", 4).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+
+    assert_eq!(admin.send("unban 127.0.0.1").await, "ok");
+    assert_eq!(admin.send("unban bob").await, "ok");
+    assert!(admin.send("unban bob").await.starts_with("error:"));
+    TestClient::connect(address, "bob", 3).await;
+}
+
+#[tokio::test]
+async fn overlong_bans_are_refused() {
+    let (address, mut admin, _server) = start_server_with_admin_socket().await;
+
+    let reply = admin.send("ban bob 99999999999999999d").await;
+    assert!(reply.starts_with("error:"), "{reply}");
+    let reply = admin.send(&format!("ban bob {}s", u64::MAX)).await;
+    assert!(reply.starts_with("error:"), "{reply}");
+
+    // the admin socket and the server keep working
+    assert_eq!(admin.send("announce still here").await, "ok");
+    TestClient::connect(address, "bob", 1).await;
+}
+
+#[tokio::test]
+async fn kicks_and_bans_apply_to_the_cluster() {
+    let bus: Arc<dyn Bus> = Arc::new(MemoryBus::default());
+    let (a, mut admin, _server) = start_node_with_admin_socket("a", bus.clone()).await;
+    let b = start_node("b", bus.clone()).await;
+    let (mut alice, _) = TestClient::connect(b, "alice", 1).await;
+    let (mut bob, _) = TestClient::connect(b, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(_)));
+
+    assert_eq!(admin.send("kick alice too loud").await, "ok");
+    assert!(matches!(alice.recv().await, Message::Kicked(kicked) if kicked.reason == "too loud"));
+    assert!(matches!(bob.recv().await, Message::UserLeft(1)));
+
+    assert_eq!(admin.send("ban bob 1h spam").await, "ok");
+    assert!(matches!(bob.recv().await, Message::Kicked(kicked) if kicked.reason == "spam"));
+    for address in [a, b] {
+        let (_, response) = TestClient::register(address, "bob", 2).await;
+        assert!(matches!(response, ConnectResponse::Reject(reason) if reason.contains("spam")));
+    }
+
+    // a node starting later hears of the ban
+    let c = start_node("c", bus).await;
+    timeout(RECEIVE_TIMEOUT, async {
+        loop {
+            let (client, response) = TestClient::register(c, "bob", 2).await;
+            if matches!(response, ConnectResponse::Reject(reason) if reason.contains("spam")) {
+                break;
+            }
+            client.close().await;
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the new node let bob in");
+
+    assert_eq!(admin.send("unban bob").await, "ok");
+    TestClient::connect(b, "bob", 2).await;
+}
+
+#[tokio::test]
+async fn flooding_clients_are_disconnected() {
+    let address = start_server_with_limits(Limits {
+        messages_per_second: 1.0,
+        message_burst: 5,
+        ..Limits::default()
+    })
+    .await;
+    let (mut alice, mut bob) = alice_and_bob(address).await;
+
+    for _ in 0..10 {
+        alice
+            .send(Message::SetPresence(PresenceMessage {
+                presence: Presence::Busy,
+                status: "typing fast".to_string(),
+            }))
+            .await;
+    }
+    assert_eq!(alice.expect_protocol_error().await, "Too many messages");
+    assert_eq!(alice.expect_close().await, CloseCode::Policy);
+    loop {
+        match bob.recv().await {
+            Message::UserUpdated(_) => (),
+            message => {
+                assert!(matches!(message, Message::UserLeft(1)));
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn clients_not_reading_are_disconnected() {
+    let address = start_server_with_limits(Limits {
+        messages_per_second: 1e6,
+        message_burst: 1_000_000,
+        queue_size: 4,
+        ..Limits::default()
+    })
+    .await;
+    // alice doesn't read anything from now on
+    let (_alice, mut bob) = alice_and_bob(address).await;
+
+    // more than the socket buffers take, so alice's queue fills up instead of anything
+    // getting dropped
+    let status = "a".repeat(60_000);
+    for _ in 0..500 {
+        bob.send(Message::SetPresence(PresenceMessage {
+            presence: Presence::Busy,
+            status: status.clone(),
+        }))
+        .await;
+    }
+    let left = timeout(Duration::from_secs(30), bob.ws.next())
+        .await
+        .expect("alice wasn't disconnected")
+        .unwrap()
+        .unwrap();
+    let left: Message = serde_json::from_str(left.to_text().unwrap()).unwrap();
+    assert!(matches!(left, Message::UserLeft(1)));
+}
+
+#[tokio::test]
+async fn oversized_sdp_ends_the_call() {
+    let address = start_server_with_limits(Limits {
+        max_sdp_size: 100,
+        ..Limits::default()
+    })
+    .await;
+    let (mut alice, mut bob) = call_in_progress(address).await;
+
+    alice
+        .send(Message::Webrtc(WebrtcMsg::Sdp {
+            type_: "offer".to_string(),
+            sdp: "a".repeat(1000),
+        }))
+        .await;
+    assert_eq!(
+        alice.expect_protocol_error().await,
+        "Session description too large"
+    );
+    assert_eq!(alice.expect_close().await, CloseCode::Size);
+    assert!(matches!(bob.recv().await, Message::CallHangup));
+    assert!(matches!(bob.recv().await, Message::UserLeft(1)));
+}
+
+#[tokio::test]
+async fn oversized_messages_are_rejected() {
+    let address = start_server_with_limits(Limits {
+        max_message_size: 1024,
+        ..Limits::default()
+    })
+    .await;
+    let (mut alice, _) = TestClient::connect(address, "alice", 1).await;
+
+    // the rest of the frame isn't read, so the connection may be reset instead of closed
+    alice
+        .ws
+        .send(WsMessage::Text("a".repeat(4096)))
+        .await
+        .unwrap();
+    assert_eq!(alice.expect_protocol_error().await, "Message too large");
+}
+
+#[tokio::test]
+async fn invalid_messages_are_rejected() {
+    let address = start_server(Config::default()).await;
+    let (mut alice, _) = TestClient::connect(address, "alice", 1).await;
+
+    alice
+        .ws
+        .send(WsMessage::Text("{\"hello\": 1}".to_string()))
+        .await
+        .unwrap();
+    assert_eq!(alice.expect_protocol_error().await, "Invalid message");
+    assert_eq!(alice.expect_close().await, CloseCode::Policy);
+}
+
+#[tokio::test]
+async fn connections_per_address_are_capped() {
+    let address = start_server_with_limits(Limits {
+        max_connections_per_ip: 2,
+        ..Limits::default()
+    })
+    .await;
+    let url = format!("ws://{address}");
+
+    let (alice, _) = TestClient::connect(address, "alice", 1).await;
+    let (_bob, _) = TestClient::connect(address, "bob", 2).await;
+    assert!(tokio_tungstenite::connect_async(&url).await.is_err());
+
+    // the slot is given back once alice's connection is gone
+    alice.close().await;
+    timeout(RECEIVE_TIMEOUT, async {
+        while tokio_tungstenite::connect_async(&url).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the connection wasn't counted out");
+}
+
+#[tokio::test]
+async fn concurrent_registrations_take_a_name_once() {
+    let address = start_server_with_limits(Limits {
+        max_connections_per_ip: 100,
+        ..Limits::default()
+    })
+    .await;
+
+    let registrations =
+        (1..=20).map(|id| async move { TestClient::register(address, "alice", id).await });
+    let responses = futures::future::join_all(registrations).await;
+    let accepted = responses
+        .iter()
+        .filter(|(_, response)| matches!(response, ConnectResponse::Accept))
+        .count();
+    assert_eq!(accepted, 1);
+}
+
+// Alice on one node and Bob on the other find each other and talk
+async fn call_across_nodes(a: SocketAddr, b: SocketAddr) {
+    let (mut alice, _) = TestClient::connect(a, "alice", 1).await;
+    let (mut bob, users) = TestClient::connect(b, "bob", 2).await;
+    assert_eq!(users, vec!["alice"]);
+    assert!(matches!(alice.recv().await, Message::UserJoined(user) if user.name == "bob"));
+
+    // names and ids are taken on all the nodes
+    let (_, response) = TestClient::register(b, "alice", 3).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+    let (_, response) = TestClient::register(b, "carol", 1).await;
+    assert!(matches!(response, ConnectResponse::Reject(_)));
+
+    alice.call(&bob).await;
+    assert!(matches!(
+        bob.recv().await,
+        Message::CallReceived(CallReceivedMessage { name }) if name == "alice"
+    ));
+    bob.answer(CallResponseMessage::Accept).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Accept)
+    ));
+
+    alice
+        .send(Message::Webrtc(WebrtcMsg::Sdp {
+            type_: "offer".to_string(),
+            sdp: "v=0".to_string(),
+        }))
+        .await;
+    assert!(matches!(
+        bob.recv().await,
+        Message::Webrtc(WebrtcMsg::Sdp { type_, .. }) if type_ == "offer"
+    ));
+
+    bob.send(Message::CallHangup).await;
+    assert!(matches!(alice.recv().await, Message::CallHangup));
+
+    bob.close().await;
+    assert!(matches!(alice.recv().await, Message::UserLeft(2)));
+}
+
+#[tokio::test]
+async fn users_call_each_other_across_nodes() {
+    let bus: Arc<dyn Bus> = Arc::new(MemoryBus::default());
+    let a = start_node("a", bus.clone()).await;
+    let b = start_node("b", bus).await;
+
+    call_across_nodes(a, b).await;
+}
+
+#[tokio::test]
+async fn calls_across_nodes_are_counted_once() {
+    let bus: Arc<dyn Bus> = Arc::new(MemoryBus::default());
+    let mut nodes = Vec::new();
+    for node in ["a", "b"] {
+        let config = Config {
+            cluster: ClusterConfig {
+                node: Some(node.to_string()),
+                ..ClusterConfig::default()
+            },
+            ..Config::default()
+        };
+        let server = Server::with_bus(config, bus.clone()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        server.spawn(listener);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin = listener.local_addr().unwrap();
+        server.spawn_admin(listener);
+        nodes.push((address, admin));
+    }
+    let ((a, a_admin), (b, b_admin)) = (nodes[0], nodes[1]);
+
+    let (mut alice, _) = TestClient::connect(a, "alice", 1).await;
+    let (mut bob, _) = TestClient::connect(b, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(_)));
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    bob.answer(CallResponseMessage::Accept).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Accept)
+    ));
+
+    let calls = |metrics: String| {
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("piperchat_active_calls "))
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(calls(get(a_admin, "/metrics").await), "1");
+    assert_eq!(calls(get(b_admin, "/metrics").await), "0");
+}
+
+#[tokio::test]
+async fn users_call_each_other_across_redis() {
+    let (address, subscribers) = start_pubsub_server().await;
+    let a = start_node("a", Arc::new(RedisBus::new(address.to_string(), None))).await;
+    let b = start_node("b", Arc::new(RedisBus::new(address.to_string(), None))).await;
+
+    // each node is subscribed to the directory and a channel of its own
+    timeout(RECEIVE_TIMEOUT, async {
+        while subscribers
+            .lock()
+            .unwrap()
+            .values()
+            .map(Vec::len)
+            .sum::<usize>()
+            < 4
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the nodes didn't subscribe");
+
+    call_across_nodes(a, b).await;
+}
+
+#[tokio::test]
+async fn lost_bus_messages_are_made_up_for() {
+    let bus: Arc<dyn Bus> = Arc::new(LossyBus {
+        lost: "\"type\":\"left\"",
+        ..LossyBus::default()
+    });
+    let a = start_node("a", bus.clone()).await;
+    let b = start_node("b", bus).await;
+    let (alice, _) = TestClient::connect(a, "alice", 1).await;
+    let (mut bob, users) = TestClient::connect(b, "bob", 2).await;
+    assert_eq!(users, vec!["alice"]);
+
+    // the next heartbeat of alice's node no longer has her
+    alice.close().await;
+    let left = timeout(Duration::from_secs(15), bob.ws.next())
+        .await
+        .expect("alice stayed on bob's node")
+        .unwrap()
+        .unwrap();
+    let left: Message = serde_json::from_str(left.to_text().unwrap()).unwrap();
+    assert!(matches!(left, Message::UserLeft(1)));
+    TestClient::connect(b, "alice", 1).await;
+}
+
+#[tokio::test]
+async fn calls_end_when_the_bus_falls_behind() {
+    let bus: Arc<dyn Bus> = Arc::new(LossyBus {
+        lost: "\"type\":\"deliver\"",
+        ..LossyBus::default()
+    });
+    let a = start_node("a", bus.clone()).await;
+    let b = start_node("b", bus).await;
+    let (mut alice, _) = TestClient::connect(a, "alice", 1).await;
+    let (bob, _) = TestClient::connect(b, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(_)));
+
+    alice.call(&bob).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Reject)
+    ));
+}
+
+#[tokio::test]
+async fn users_with_the_same_id_on_other_nodes_are_kept_apart() {
+    let bus = Arc::new(MemoryBus::default());
+    let a = start_node("a", bus.clone()).await;
+    let b = start_node("b", bus.clone()).await;
+    let (_alice, _) = TestClient::connect(a, "alice", 1).await;
+
+    // a third node let someone in with alice's id before it heard of her, and they leave
+    for message in [
+        r#"{"node":"c","type":"joined","user":{"id":1,"name":"mallory","presence":"available","status":""}}"#,
+        r#"{"node":"c","type":"left","id":1}"#,
+    ] {
+        bus.publish("piperchat:directory", message.as_bytes().to_vec());
+    }
+
+    let (_bob, users) = TestClient::connect(b, "bob", 2).await;
+    assert_eq!(users, vec!["alice"]);
+}
+
+#[tokio::test]
+async fn calls_are_kept_when_someone_with_the_peers_id_leaves() {
+    let bus = Arc::new(MemoryBus::default());
+    let a = start_node("a", bus.clone()).await;
+    let b = start_node("b", bus.clone()).await;
+    let (mut alice, _) = TestClient::connect(a, "alice", 1).await;
+    let (mut bob, _) = TestClient::connect(b, "bob", 2).await;
+    assert!(matches!(alice.recv().await, Message::UserJoined(_)));
+    alice.call(&bob).await;
+    assert!(matches!(bob.recv().await, Message::CallReceived(_)));
+    bob.answer(CallResponseMessage::Accept).await;
+    assert!(matches!(
+        alice.recv().await,
+        Message::CallResponse(CallResponseMessage::Accept)
+    ));
+
+    // a third node had someone with bob's id, who leaves
+    for message in [
+        r#"{"node":"c","type":"joined","user":{"id":2,"name":"mallory","presence":"available","status":""}}"#,
+        r#"{"node":"c","type":"left","id":2}"#,
+    ] {
+        bus.publish("piperchat:directory", message.as_bytes().to_vec());
+    }
+
+    bob.send(Message::Webrtc(WebrtcMsg::Sdp {
+        type_: "offer".to_string(),
+        sdp: "v=0".to_string(),
+    }))
+    .await;
+    loop {
+        match alice.recv().await {
+            Message::UserJoined(_) | Message::UserLeft(_) => (),
+            message => {
+                assert!(matches!(message, Message::Webrtc(_)), "{message:?}");
+                break;
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn callers_are_told_when_the_node_of_who_they_call_dies() {
+    let bus = Arc::new(MemoryBus::default());
+    let a = start_node("a", bus.clone()).await;
+    let (mut alice, _) = TestClient::connect(a, "alice", 1).await;
+
+    // a node with carol, which stops while her phone rings
+    let joined = r#"{"node":"c","type":"joined","user":{"id":3,"name":"carol","presence":"available","status":""}}"#;
+    bus.publish("piperchat:directory", joined.as_bytes().to_vec());
+    assert!(matches!(alice.recv().await, Message::UserJoined(_)));
+    alice.send(Message::Call(CallMessage { peer: 3 })).await;
+
+    // well before the call would time out
+    let response = timeout(Duration::from_secs(25), async {
+        loop {
+            let message = alice.ws.next().await.unwrap().unwrap();
+            match serde_json::from_str(message.to_text().unwrap()).unwrap() {
+                Message::UserLeft(_) => (),
+                message => return message,
+            }
+        }
+    })
+    .await
+    .expect("the call wasn't rejected");
+    assert!(matches!(
+        response,
+        Message::CallResponse(CallResponseMessage::Reject)
+    ));
+}
+
+// Check that the bus gives up on a server replying with `reply` and connects again
+async fn redis_refuses(reply: &'static [u8]) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let bus = RedisBus::new(address.to_string(), None);
+    let _messages = bus.subscribe("piperchat:directory");
+
+    timeout(Duration::from_secs(10), async {
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            // the bus may hang up before reading it all, the server never does
+            tokio::spawn(async move {
+                stream.write_all(reply).await.ok();
+                std::future::pending::<()>().await;
+                drop(stream);
+            });
+        }
+    })
+    .await
+    .expect("the bus didn't reconnect");
+}
+
+#[tokio::test]
+async fn oversized_redis_replies_are_refused() {
+    // a value of an exabyte
+    redis_refuses(b"*3\r\n$9\r\nsubscribe\r\n$1152921504606846976\r\n").await;
+}
+
+#[tokio::test]
+async fn overlong_redis_lines_are_refused() {
+    static LINE: [u8; 1 << 20] = [b'+'; 1 << 20];
+    redis_refuses(&LINE).await;
+}
+
+#[tokio::test]
+async fn publications_are_dropped_while_redis_is_unreachable() {
+    // nothing listens there for now
+    let address = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let bus = RedisBus::new(address.to_string(), None);
+    let mut messages = bus.subscribe("channel");
+    for _ in 0..10 {
+        bus.publish("channel", b"stale".to_vec());
+    }
+    sleep(Duration::from_secs(3)).await;
+
+    let subscribers = serve_pubsub(TcpListener::bind(address).await.unwrap());
+    timeout(RECEIVE_TIMEOUT, async {
+        while subscribers.lock().unwrap().is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the bus didn't subscribe");
+    bus.publish("channel", b"fresh".to_vec());
+
+    let message = timeout(RECEIVE_TIMEOUT, messages.recv()).await.unwrap();
+    assert_eq!(message.unwrap(), b"fresh");
+}